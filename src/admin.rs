@@ -0,0 +1,182 @@
+// Copyright (C) 2019-2022 Aleo Systems Inc.
+// This file is part of the aoraki-labs library.
+
+// The aoraki-labs library is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// The aoraki-labs library is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with the aoraki-labs library. If not, see <https://www.gnu.org/licenses/>.
+
+//! Operator controls, served as the admin-only `admin_*` RPC methods.
+//!
+//! Ingestion (ReceiveTask/ReceiveTasks), processing of the task queue and the
+//! dummy task loop can be paused independently. Draining stops ingestion and
+//! keeps processing until the queue is empty.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::RwLock;
+
+use jsonrpc_http_server::jsonrpc_core::{MetaIoHandler, Params, Value};
+use lazy_static::lazy_static;
+use log::*;
+use serde_json::json;
+
+use crate::auth::{AuthMiddleware, Caller};
+use crate::config;
+use crate::error::{invalid_params, rpc_error, ErrorCode};
+use crate::logger;
+use crate::network;
+use crate::queue::TASK_QUEUE;
+use crate::worker;
+
+static INGESTION_PAUSED: AtomicBool = AtomicBool::new(false);
+static PROCESSING_PAUSED: AtomicBool = AtomicBool::new(false);
+static DUMMY_PAUSED: AtomicBool = AtomicBool::new(false);
+static DRAINING: AtomicBool = AtomicBool::new(false);
+
+const TARGETS: [&str; 3] = ["ingestion", "processing", "dummy"];
+const REDACTED: &str = "<redacted>";
+
+lazy_static! {
+    static ref CLI_SETTINGS: RwLock<serde_json::Map<String, Value>> = {   //command line options the requestor was started with
+        RwLock::new(serde_json::Map::new())
+    };
+}
+
+pub fn ingestion_paused() -> bool {
+    INGESTION_PAUSED.load(Ordering::SeqCst)
+}
+
+pub fn processing_paused() -> bool {
+    PROCESSING_PAUSED.load(Ordering::SeqCst)
+}
+
+pub fn dummy_paused() -> bool {
+    DUMMY_PAUSED.load(Ordering::SeqCst)
+}
+
+/// remember the command line options for `admin_getConfig`, secrets must already be left out
+pub fn set_cli_settings(settings: serde_json::Map<String, Value>) {
+    *CLI_SETTINGS.write().unwrap() = settings;
+}
+
+fn flag(target: &str) -> &'static AtomicBool {
+    match target {
+        "ingestion" => &INGESTION_PAUSED,
+        "processing" => &PROCESSING_PAUSED,
+        _ => &DUMMY_PAUSED,
+    }
+}
+
+/// `[]` means every target, otherwise a list of target names
+fn parse_targets(params: Params) -> Result<Vec<String>, jsonrpc_http_server::jsonrpc_core::Error> {
+    let targets: Vec<String> = match params {
+        Params::None => Vec::new(),
+        p => p.parse().map_err(|_| invalid_params("expected [target, ...]"))?,
+    };
+    if targets.is_empty() {
+        return Ok(TARGETS.iter().map(|t| t.to_string()).collect());
+    }
+    for t in targets.iter() {
+        if !TARGETS.contains(&t.as_str()) {
+            return Err(invalid_params(&format!("unknown target {}, expected one of {}", t, TARGETS.join(", "))));
+        }
+    }
+    Ok(targets)
+}
+
+async fn status() -> Value {
+    let (queued, delayed) = {
+        let queue = TASK_QUEUE.lock().await;
+        (queue.len(), queue.delayed())
+    };
+    let draining = DRAINING.load(Ordering::SeqCst);
+    json!({
+        "ingestion_paused": ingestion_paused(),
+        "processing_paused": processing_paused(),
+        "dummy_paused": dummy_paused(),
+        "draining": draining,
+        "drained": draining && queued == 0,
+        "queued": queued,
+        "delayed": delayed,
+        "processing": worker::busy(),
+        "log_filter": logger::current_filter(),
+    })
+}
+
+/// node urls carry api keys in their path, query or user info, only keep scheme and host
+pub fn redact_url(url: &str) -> String {
+    match url.split_once("://") {
+        Some((scheme, rest)) => {
+            let authority = rest.split(['/', '?', '#']).next().unwrap_or_default();
+            let host = authority.rsplit_once('@').map_or(authority, |(_, h)| h);
+            if host.len() == rest.len() {
+                url.to_string()
+            } else {
+                format!("{}://{}/{}", scheme, host, REDACTED)
+            }
+        },
+        None => REDACTED.to_string(),
+    }
+}
+
+pub fn add_admin_methods(io: &mut MetaIoHandler<Caller, AuthMiddleware>) {
+    io.add_method_with_meta("admin_status", |_params: Params, _caller: Caller| async move {
+        Ok(status().await)
+    });
+    io.add_method_with_meta("admin_pause", |params: Params, caller: Caller| async move {   //pause ingestion, processing and/or the dummy task
+        for t in parse_targets(params)? {
+            flag(&t).store(true, Ordering::SeqCst);
+            warn!("{} paused by {}", t, caller.display_name());
+        }
+        Ok(status().await)
+    });
+    io.add_method_with_meta("admin_resume", |params: Params, caller: Caller| async move {
+        for t in parse_targets(params)? {
+            flag(&t).store(false, Ordering::SeqCst);
+            if t == "ingestion" {
+                DRAINING.store(false, Ordering::SeqCst);
+            }
+            warn!("{} resumed by {}", t, caller.display_name());
+        }
+        Ok(status().await)
+    });
+    io.add_method_with_meta("admin_drain", |_params: Params, caller: Caller| async move {   //stop taking tasks, finish the queued ones
+        INGESTION_PAUSED.store(true, Ordering::SeqCst);
+        PROCESSING_PAUSED.store(false, Ordering::SeqCst);
+        DRAINING.store(true, Ordering::SeqCst);
+        warn!("draining the task queue, requested by {}", caller.display_name());
+        Ok(status().await)
+    });
+    io.add_method_with_meta("admin_setLogLevel", |params: Params, caller: Caller| async move {   //RUST_LOG style filter, e.g. "debug"
+        let req_input: Vec<String> = params.parse().map_err(|_| invalid_params("expected [filter]"))?;
+        if req_input.len() != 1 {
+            return Err(invalid_params("expected exactly one log filter"))
+        }
+        logger::set_filter(&req_input[0]).map_err(|e| invalid_params(&e))?;
+        warn!("log filter set to {} by {}", req_input[0], caller.display_name());
+        Ok(Value::String(logger::current_filter()))
+    });
+    io.add_method_with_meta("admin_getConfig", |_params: Params, _caller: Caller| async move {   //effective settings, secrets redacted
+        let mut current = config::current();
+        let networks = current.networks.values_mut().flat_map(|n| n.endpoints.iter_mut());
+        for e in current.chain.endpoints.iter_mut().chain(networks) {
+            e.url = redact_url(&e.url);
+        }
+        let config = serde_json::to_value(current)
+            .map_err(|e| rpc_error(ErrorCode::Internal, &e.to_string(), None))?;
+        Ok(json!({
+            "config": config,
+            "cli": Value::Object(CLI_SETTINGS.read().unwrap().clone()),
+            "networks": network::describe(),
+            "private_key": REDACTED,
+        }))
+    });
+}
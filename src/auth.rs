@@ -0,0 +1,189 @@
+// Copyright (C) 2019-2022 Aleo Systems Inc.
+// This file is part of the aoraki-labs library.
+
+// The aoraki-labs library is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// The aoraki-labs library is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with the aoraki-labs library. If not, see <https://www.gnu.org/licenses/>.
+
+//! API key authentication for the RPC servers.
+//!
+//! Clients send `Authorization: Bearer <key>`. Only `hex(keccak256(key))` is kept
+//! in the config, and every method requires a minimum role. With `auth.disabled`
+//! callers without a valid key are treated as submitters, never as admins.
+
+use std::future::Future;
+use std::pin::Pin;
+
+use jsonrpc_http_server::jsonrpc_core::futures::future::{self, Either};
+use jsonrpc_http_server::jsonrpc_core::{Call, Error, Metadata, Middleware, Output};
+use jsonrpc_http_server::hyper;
+use serde_derive::{Deserialize, Serialize};
+use web3::signing::keccak256;
+
+use crate::config::CONFIG;
+use crate::error::{rpc_error, ErrorCode};
+
+/// roles are ordered, each one may do everything the ones below it may
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    Reader,
+    Submitter,
+    Admin,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Reader => "reader",
+            Role::Submitter => "submitter",
+            Role::Admin => "admin",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiKey {
+    pub name: String,
+    /// hex(keccak256(key))
+    pub key_hash: String,
+    pub role: Role,
+}
+
+/// who is calling, attached to every RPC request
+#[derive(Debug, Clone, Default)]
+pub struct Caller {
+    /// the api key name, `None` when no valid key was presented
+    pub name: Option<String>,
+    pub role: Option<Role>,
+    /// source address as reported by a trusted fronting proxy
+    pub address: Option<String>,
+}
+
+impl Metadata for Caller {}
+
+impl Caller {
+    pub fn display_name(&self) -> &str {
+        self.name.as_deref().unwrap_or("anonymous")
+    }
+
+    /// what quotas are accounted against: the api key name, else the source address, else
+    /// the one bucket all anonymous callers share
+    pub fn client_id(&self) -> String {
+        match (&self.name, &self.address) {
+            (Some(name), _) => name.clone(),
+            (None, Some(addr)) => addr.clone(),
+            (None, None) => "anonymous".to_string(),
+        }
+    }
+}
+
+pub fn hash_key(key: &str) -> String {
+    hex::encode(keccak256(key.as_bytes()))
+}
+
+/// resolve a bearer token against the configured api keys
+pub fn authenticate(bearer: Option<&str>) -> Caller {
+    let config = CONFIG.read().unwrap();
+    let anonymous = Caller {
+        role: if config.auth.disabled { Some(Role::Submitter) } else { None },
+        ..Caller::default()
+    };
+    let key = match bearer {
+        Some(k) => k,
+        None => return anonymous,
+    };
+    let hash = hash_key(key);
+    match config.auth.api_keys.iter().find(|k| k.key_hash.trim_start_matches("0x").eq_ignore_ascii_case(&hash)) {
+        Some(k) => Caller { name: Some(k.name.clone()), role: Some(k.role), address: None },
+        None => anonymous,
+    }
+}
+
+/// the token of an `Authorization: Bearer <key>` header value
+pub fn bearer_token(header: &[u8]) -> Option<&str> {
+    let value = std::str::from_utf8(header).ok()?.trim();
+    let (scheme, token) = value.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("bearer") {
+        return None;
+    }
+    Some(token.trim())
+}
+
+pub fn http_caller(req: &hyper::Request<hyper::Body>) -> Caller {
+    let header = req.headers().get(hyper::header::AUTHORIZATION).map(|h| h.as_bytes());
+    let mut caller = authenticate(header.and_then(bearer_token));
+    // the http server does not expose the peer address, rely on the proxy in front of it;
+    // the headers are client controlled without one, so they are only used when trusted
+    if !CONFIG.read().unwrap().quota.trust_forwarded_for {
+        return caller;
+    }
+    caller.address = req.headers().get("x-forwarded-for")
+        .or_else(|| req.headers().get("x-real-ip"))
+        .and_then(|h| h.to_str().ok())
+        .and_then(|v| v.split(',').next())
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty());
+    caller
+}
+
+/// the lowest role allowed to call `method`; anything not listed is admin only
+pub fn required_role(method: &str) -> Role {
+    match method {
+        "GetTaskStatus" | "ListTasks" | "GetUsage" => Role::Reader,
+        "ReceiveTask" | "ReceiveTasks" | "CancelTask" | "SimulateTask" => Role::Submitter,
+        _ => Role::Admin,
+    }
+}
+
+pub fn authorize(caller: &Caller, required: Role) -> Result<(), Error> {
+    match caller.role {
+        None => Err(rpc_error(ErrorCode::Unauthorized, "missing or unknown api key", None)),
+        Some(role) if role < required => Err(rpc_error(
+            ErrorCode::Forbidden,
+            &format!("this method requires the {} role", required.as_str()),
+            None,
+        )),
+        Some(_) => Ok(()),
+    }
+}
+
+/// checks every call of a request (including each call of a batch) before it is dispatched
+#[derive(Debug, Default)]
+pub struct AuthMiddleware;
+
+type CallFuture = Pin<Box<dyn Future<Output = Option<Output>> + Send>>;
+type RequestFuture = Pin<Box<dyn Future<Output = Option<jsonrpc_http_server::jsonrpc_core::Response>> + Send>>;
+
+impl Middleware<Caller> for AuthMiddleware {
+    type Future = RequestFuture;
+    type CallFuture = CallFuture;
+
+    fn on_call<F, X>(&self, call: Call, caller: Caller, next: F) -> Either<Self::CallFuture, X>
+    where
+        F: Fn(Call, Caller) -> X + Send + Sync,
+        X: Future<Output = Option<Output>> + Send + 'static,
+    {
+        let denied = match &call {
+            Call::MethodCall(m) => authorize(&caller, required_role(&m.method))
+                .err()
+                .map(|e| Some(Output::from(Err(e), m.id.clone(), m.jsonrpc))),
+            // notifications get no response, a denied one is just dropped
+            Call::Notification(n) => authorize(&caller, required_role(&n.method)).err().map(|_| None),
+            Call::Invalid { .. } => None,
+        };
+        match denied {
+            Some(output) => Either::Left(Box::pin(future::ready(output))),
+            None => Either::Right(next(call, caller)),
+        }
+    }
+}
@@ -15,31 +15,47 @@
 // along with the aoraki-labs library. If not, see <https://www.gnu.org/licenses/>.
 
 
-use std::{str::FromStr, sync::Arc, collections::VecDeque};
+use std::str::FromStr;
 use log::*;
 use core::str;
-use rand::seq::SliceRandom;
 use serde_derive::{Deserialize,Serialize};
-use ethereum_private_key_to_address::PrivateKey;
 use chrono::{Utc};
 
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicI64, Ordering};
 use std::time::{SystemTime, UNIX_EPOCH};
 
 use reqwest::Client;
 
+use jsonrpc_http_server::jsonrpc_core;
+
 use web3::{
     ethabi::{ethereum_types::U256,Function, ParamType, Param, StateMutability, Token},
-    types::{Address,Bytes, TransactionParameters, TransactionRequest}, signing::keccak256,
+    types::{Address, BlockId, BlockNumber, Bytes, CallRequest, FeeHistory, H256, TransactionId, TransactionParameters, TransactionReceipt, U64},
 };
 
 use tokio::time::{self,Duration};
 
-use web3::types::BlockNumber::{Latest,Pending};
+use web3::signing::Key;
+use web3::types::BlockNumber::Pending;
 use lazy_static::lazy_static;
 
+use crate::config;
+use crate::network::{self, Network};
+use crate::metrics::{self, MeteredHttp};
+use crate::queue;
+use crate::task::{self, Task, TaskParams, TaskState};
+
 const MAX_RETRIES: u32 = 5;
 const GAS_PRICE_INCREMENT_PERCENTAGE: u32 = 20; // Increase gas price by 20% on each retry
 const GAS_INCREMENT_PERCENTAGE: u32 = 20; // Increase gas by 20% on each retry
+const EIP1559_TX_TYPE: u64 = 2;
+const FEE_HISTORY_BLOCKS: u64 = 10; // Recent blocks the priority fee is taken from
+const PRIORITY_FEE_PERCENTILE: f64 = 50.0; // Percentile of the priority fees paid within each block
+const DEFAULT_PRIORITY_FEE: u64 = 1_000_000_000; // 1 gwei tip when recent blocks paid none
+const TX_DROP_TIMEOUT_SECS: i64 = 900; // A broadcast tx the node no longer knows after this long is treated as dropped
+
+pub static LAST_SUBMISSION: AtomicI64 = AtomicI64::new(0);  //unix seconds of the last broadcast submitTask tx
 
 lazy_static! {
     pub static ref PRIV_KEY: tokio::sync::Mutex<String> = {      //priv_key
@@ -48,39 +64,14 @@ lazy_static! {
     pub static ref RELAYER_URL: tokio::sync::Mutex<String> = {   //relayer rpc url
         tokio::sync::Mutex::new(String::from(""))
     };
-    pub static ref TASK_MSG_QUEUE: Arc<tokio::sync::Mutex<VecDeque<String>>> = {
-        Arc::new(tokio::sync::Mutex::new(VecDeque::new()))
-    };
-    pub static ref CONTRACT: tokio::sync::Mutex<String> = {      //contract
-        tokio::sync::Mutex::new(String::from(""))
-      };
 }
 
 
-///config chain urls
-pub const SEPOLIA_CHAIN_URLS: [&str; 1] = [
-    "https://eth-sepolia.g.alchemy.com/v2/kMO8lL7g44IJOGR-Om-kc7DAlmHaXFb7",
-];
-
-
 //Onchain paramter
 pub const  GAS_UPPER : &str = "1000000";
 // pub const  CONTRACT_ADDR :&str = "0xc20F6905A21c26B106c7A30E77e4711390cffBA8";
 
 
-//Dummy task info
-// Dev env
-//const REWARD_TOKEN:&str="0xfDfd239c9dD30445d0e080Ecf055A5cc53456A72";
-// Prod env
-const REWARD_TOKEN:&str="0x0622118429C54577eF34229526661c41020048bF";
-const REWARD:u64 = 100;
-// Dev env
-//const LIABILITY_TOKEN:&str="0xfDfd239c9dD30445d0e080Ecf055A5cc53456A72";
-// Prod env
-const LIABILITY_TOKEN:&str="0x0622118429C54577eF34229526661c41020048bF";
-const LIABILITY:u64 = 100;
-const LIABILITY_WINDOW:u64=36000;
-
 #[derive(Debug, Serialize, Deserialize)]
 struct RpcRequest {
     jsonrpc: String,
@@ -90,7 +81,7 @@ struct RpcRequest {
 }
 
 #[derive(Debug, Serialize, Deserialize,Default,Clone)]
-struct TaskResponse {
+pub struct TaskResponse {
     pub prover: String,
     pub instance: String,
     pub reward_token: String,
@@ -110,11 +101,26 @@ struct RpcResponse {
     id: u64,
 }
 
-/// get the account nonce value
-pub async fn get_nonce(addr:Address) -> U256{
+/// get the account nonce value, retried with a growing delay until an endpoint answers
+pub async fn get_nonce(net:&Network, addr:Address) -> Result<U256, String>{
+    let mut attempts = 0;
     loop {
-        for url in SEPOLIA_CHAIN_URLS.iter() {
-            let transport = match web3::transports::Http::new(&url){
+        if let Some(nonce) = query_nonce(net, addr).await {
+            return Ok(nonce)
+        }
+        attempts += 1;
+        if attempts >= MAX_RETRIES {
+            return Err(format!("no endpoint of {} answered the nonce of {:?}", net.name, addr));
+        }
+        metrics::SUBMIT_RETRIES.with_label_values(&["nonce_query"]).inc();
+        time::sleep(Duration::from_secs(2u64.pow(attempts))).await;
+    }
+}
+
+/// the pending nonce of `addr` from the first endpoint that answers
+async fn query_nonce(net:&Network, addr:Address) -> Option<U256>{
+        for url in net.urls() {
+            let transport = match MeteredHttp::new(&url){
                 Ok(r)=>{r},
                 Err(_e) => {
                     continue;
@@ -129,36 +135,275 @@ pub async fn get_nonce(addr:Address) -> U256{
                 },
             };
             info!("nonce value is {:?}",nonce.clone());
-            return nonce
+            return Some(nonce)
+    }
+    None
+}
+
+/// fees of a submitTask tx
+#[derive(Debug, Clone, Copy)]
+pub enum GasFees {
+    /// type-2 tx on chains with EIP-1559
+    Dynamic { max_fee: U256, priority_fee: U256 },
+    /// legacy tx on chains without a base fee
+    Legacy { gas_price: U256 },
+}
+
+impl GasFees {
+    /// the most a unit of gas may cost
+    pub fn max_price(&self) -> U256 {
+        match *self {
+            GasFees::Dynamic { max_fee, .. } => max_fee,
+            GasFees::Legacy { gas_price } => gas_price,
+        }
+    }
+
+    /// raised by `percent`, a replacement tx must outbid the pending one on every fee
+    fn bump(self, percent: u32) -> Self {
+        let raise = |fee: U256| fee * (100 + percent) / 100;
+        match self {
+            GasFees::Dynamic { max_fee, priority_fee } => GasFees::Dynamic { max_fee: raise(max_fee), priority_fee: raise(priority_fee) },
+            GasFees::Legacy { gas_price } => GasFees::Legacy { gas_price: raise(gas_price) },
+        }
+    }
+
+    fn apply(&self, tx: &mut TransactionParameters) {
+        match *self {
+            GasFees::Dynamic { max_fee, priority_fee } => {
+                tx.transaction_type = Some(U64::from(EIP1559_TX_TYPE));
+                tx.max_fee_per_gas = Some(max_fee);
+                tx.max_priority_fee_per_gas = Some(priority_fee);
+            },
+            GasFees::Legacy { gas_price } => tx.gas_price = Some(gas_price),
+        }
+    }
+}
+
+/// median of the priority fees paid in recent blocks, empty blocks left out
+fn priority_fee(history: &FeeHistory) -> U256 {
+    let mut rewards: Vec<U256> = history.reward.iter().flatten()
+        .filter_map(|r| r.first().copied())
+        .filter(|r| !r.is_zero())
+        .collect();
+    if rewards.is_empty() {
+        return U256::from(DEFAULT_PRIORITY_FEE)
     }
- }
+    rewards.sort();
+    rewards[rewards.len() / 2]
 }
 
-/// 1.1 multiple of the network gas
-pub async fn gas_price() -> U256{
+/// fees for the next block, retried with a growing delay until an endpoint answers
+pub async fn gas_fees(net:&Network) -> Result<GasFees, String>{
+    let mut attempts = 0;
     loop {
-        for url in SEPOLIA_CHAIN_URLS.iter() {
-            let transport = match web3::transports::Http::new(&url){
+        if let Some(fees) = query_gas_fees(net).await {
+            return Ok(fees)
+        }
+        attempts += 1;
+        if attempts >= MAX_RETRIES {
+            return Err(format!("no endpoint of {} answered the gas fees", net.name));
+        }
+        metrics::SUBMIT_RETRIES.with_label_values(&["fees_query"]).inc();
+        time::sleep(Duration::from_secs(2u64.pow(attempts))).await;
+    }
+}
+
+/// fees for the next block from the first endpoint that answers: the max fee covers the
+/// base fee doubling (six full blocks) on top of the usual tip, legacy pricing is 1.1
+/// multiple of the network gas price
+pub async fn query_gas_fees(net:&Network) -> Option<GasFees>{
+        for url in net.urls() {
+            let transport = match MeteredHttp::new(&url){
                 Ok(r)=>{r},
                 Err(_e) => {
                     continue;
                 },
             };
             let web3 = web3::Web3::new(transport);
-            let gas_price= match web3.eth().gas_price().await{
-                Ok(r) => r,
-                Err(_) => continue,
+            let latest = match web3.eth().block(BlockId::Number(BlockNumber::Latest)).await{
+                Ok(Some(b)) => b,
+                _ => continue,
             };
+            let fees = match latest.base_fee_per_gas {
+                Some(base_fee) => {
+                    let history = web3.eth().fee_history(U256::from(FEE_HISTORY_BLOCKS), BlockNumber::Latest, Some(vec![PRIORITY_FEE_PERCENTILE])).await;
+                    let (next_base_fee, priority_fee) = match history {
+                        Ok(h) => (h.base_fee_per_gas.last().copied().unwrap_or(base_fee), priority_fee(&h)),
+                        Err(web3::Error::Rpc(e)) => {   //a node without eth_feeHistory
+                            warn!("eth_feeHistory failed, using the default priority fee: {}", e.message);
+                            (base_fee, U256::from(DEFAULT_PRIORITY_FEE))
+                        },
+                        Err(_) => continue,
+                    };
+                    GasFees::Dynamic { max_fee: next_base_fee * 2 + priority_fee, priority_fee }
+                },
+                None => {
+                    let gas_price= match web3.eth().gas_price().await{
+                        Ok(r) => r,
+                        Err(_) => continue,
+                    };
+                    GasFees::Legacy { gas_price: gas_price * 110 / 100 }
+                },
+            };
+            info!("gas fees on {} are {:?}", net.name, fees);
+            return Some(fees)
+    }
+    None
+}
+
+/// the account the submitTask txs are sent from
+pub async fn signer_address() -> Result<Address, String> {
+    let key = PRIV_KEY.lock().await.clone();
+    let secret = signing_key(&key)?;
+    Ok(web3::signing::SecretKeyRef::new(&secret).address())
+}
+
+/// parse the configured private key, with or without its 0x prefix
+fn signing_key(key: &str) -> Result<web3::signing::SecretKey, String> {
+    web3::signing::SecretKey::from_str(key.trim_start_matches("0x")).map_err(|e| format!("invalid private key: {}", e))
+}
+
+/// poll the receipt of every broadcast submitTask tx and move its task towards Settled
+pub async fn watch_receipts(confirmations: u64) -> Result<(), String> {
+    let mut watched: HashMap<Option<String>, Vec<Task>> = HashMap::new();   //network -> its broadcast tasks
+    for t in task::TASKS.lock().await.values() {
+        if matches!(t.state, TaskState::Broadcast | TaskState::Mined) {
+            watched.entry(t.network.clone()).or_default().push(t.clone());
+        }
+    }
+
+    let mut failed = Vec::new();
+    for (network, tasks) in watched {
+        if let Err(e) = watch_network_receipts(network.as_deref(), tasks, confirmations).await {
+            failed.push(e);
+        }
+    }
+    if !failed.is_empty() {
+        return Err(failed.join("; "))
+    }
+    Ok(())
+}
 
-            let upper_gas = (gas_price.as_u64())*(100)/(50);
-            info!("gas price value is {:?}",upper_gas.clone());
-            return U256::from_dec_str(&upper_gas.to_string()).unwrap()      
+async fn watch_network_receipts(network: Option<&str>, tasks: Vec<Task>, confirmations: u64) -> Result<(), String> {
+    let net = network::get(network)?;
+    let web3 = net.client()?;
+    let latest = match web3.eth().block_number().await {
+        Ok(r) => r.as_u64(),
+        Err(e) => return Err(network::redact(format!("get block number on {} failed: {}", net.name, e))),
+    };
+    for t in tasks {
+        match check_receipt(&net, &web3, &t, latest, confirmations).await {
+            Ok(Some(updated)) => queue::persist_task(updated).await,
+            Ok(None) => (),
+            Err(e) => warn!("check the receipt of task {} failed: {}", t.task_key, network::redact(e)),
+        }
     }
- }
+    Ok(())
 }
 
-/// submit proof data to sepolia chain
-pub async fn submit_task(  
+fn apply_receipt(t: &mut Task, receipt: &TransactionReceipt, depth: u64) {
+    t.block_number = receipt.block_number.map(|n| n.as_u64());
+    t.gas_used = receipt.gas_used.map(|g| g.low_u64());
+    t.receipt_status = receipt.status.map(|s| s.as_u64());
+    t.confirmations = Some(depth);
+}
+
+/// returns the updated task record when anything changed
+async fn check_receipt(
+    net: &Network,
+    web3: &web3::Web3<MeteredHttp>,
+    t: &Task,
+    latest: u64,
+    confirmations: u64,
+) -> Result<Option<Task>, String> {
+    let tx_hash = match &t.tx_hash {
+        Some(h) => H256::from_str(h).map_err(|e| format!("invalid tx hash {}: {}", h, e))?,
+        None => return Ok(None),
+    };
+    let receipt = web3.eth().transaction_receipt(tx_hash).await.map_err(|e| e.to_string())?;
+
+    let receipt = match receipt {
+        Some(r) if r.block_number.is_some() => r,
+        _ => {
+            let now = Utc::now().timestamp();
+            if t.state == TaskState::Mined {
+                warn!("tx {:?} of task {} is no longer mined, waiting for it again", tx_hash, t.task_key);
+                return task::transition(&t.task_key, TaskState::Broadcast, |t| {
+                    t.block_number = None;
+                    t.gas_used = None;
+                    t.receipt_status = None;
+                    t.confirmations = None;
+                }).await.map(Some)
+            }
+            if t.expiry.is_some_and(|e| (e as i64) < now) {
+                return task::transition(&t.task_key, TaskState::Expired, |t| {
+                    t.error = Some("relayer assignment expired before the submitTask tx was mined".to_string())
+                }).await.map(Some)
+            }
+            if now - t.updated_at > TX_DROP_TIMEOUT_SECS {
+                let known = web3.eth().transaction(TransactionId::Hash(tx_hash)).await.map_err(|e| e.to_string())?;
+                if known.is_none() {
+                    // its nonce is free again, later txs would wait behind the gap forever
+                    net.forget_nonce().await;
+                    return task::transition(&t.task_key, TaskState::Failed, |t| {
+                        t.error = Some("submitTask tx was dropped".to_string())
+                    }).await.map(Some)
+                }
+            }
+            return Ok(None)
+        }
+    };
+
+    let block_number = receipt.block_number.unwrap().as_u64();
+    let depth = latest.saturating_sub(block_number) + 1;
+
+    let gas_used = receipt.gas_used.map(|g| g.low_u64()).unwrap_or_default() as f64;
+    if receipt.status.map(|s| s.as_u64()) == Some(0) {
+        metrics::GAS_USED.with_label_values(&["reverted"]).inc_by(gas_used);
+        error!("submitTask tx {:?} of task {} reverted in block {}", tx_hash, t.task_key, block_number);
+        return task::transition(&t.task_key, TaskState::Failed, |t| {
+            apply_receipt(t, &receipt, depth);
+            t.error = Some(format!("submitTask reverted in block {} (status 0)", block_number));
+        }).await.map(Some)
+    }
+
+    let mut updated = if t.state == TaskState::Broadcast {
+        info!("submitTask tx {:?} of task {} mined in block {}", tx_hash, t.task_key, block_number);
+        metrics::GAS_USED.with_label_values(&["success"]).inc_by(gas_used);
+        task::transition(&t.task_key, TaskState::Mined, |t| apply_receipt(t, &receipt, depth)).await?
+    } else if t.confirmations != Some(depth) {
+        task::update(&t.task_key, |t| apply_receipt(t, &receipt, depth)).await?
+    } else {
+        t.clone()
+    };
+
+    if depth >= confirmations {
+        updated = task::transition(&t.task_key, TaskState::Settled, |_| ()).await?;
+    }
+    Ok(Some(updated))
+}
+
+/// the submitTask calldata for the terms the relayer assigned
+pub fn submit_task_calldata(resp:&TaskResponse) -> Result<Vec<u8>, String> {
+    let instance=Bytes::from(resp.instance.clone());
+    let addr=Address::from_str(resp.prover.as_str()).map_err(|e| format!("invalid prover address: {}", e))?;
+    let reward_token=Address::from_str(resp.reward_token.as_str()).map_err(|e| format!("invalid reward token: {}", e))?;
+    let reward = U256::from(resp.reward);
+    let liability_window = resp.liability_window;
+    let liability_token=Address::from_str(resp.liability_token.as_str()).map_err(|e| format!("invalid liability token: {}", e))?;
+    let liability_amount = U256::from(resp.liability);
+    let expiry = resp.expiry;
+
+    let sig_bytes = hex::decode(&resp.signature).map_err(|e| format!("invalid relayer signature: {}", e))?;
+    let signature=Bytes::from(sig_bytes.clone());
+
+    info!("receive relayer response signature:{:?}",hex::encode(sig_bytes));
+    encode_submit_task(instance,addr,reward_token,reward,liability_window,liability_token,liability_amount,expiry,signature)
+}
+
+/// abi encoded submitTask call
+#[allow(clippy::too_many_arguments)]
+pub fn encode_submit_task(  
     instance:Bytes,
     prover:Address,
     reward_token:Address,
@@ -168,16 +413,8 @@ pub async fn submit_task(
     liability_amount:U256,
     expiry:u64,
     signature:Bytes
-) -> Result<String, String> { 
-
-    let url_str = SEPOLIA_CHAIN_URLS.choose(&mut rand::thread_rng()).unwrap();
-    let transport = web3::transports::Http::new(url_str).unwrap();
-    let web3 = web3::Web3::new(transport);
-
-    let ctr = CONTRACT.lock().await;
-    let ctr_addr = (*ctr).clone();
-    let contract_address = Address::from_str(ctr_addr.as_str()).unwrap();
-
+) -> Result<Vec<u8>, String> {
+    #[allow(deprecated)]
     let func = Function {
         name: "submitTask".to_owned(),
         inputs: vec![
@@ -198,76 +435,138 @@ pub async fn submit_task(
     };
 
       //enocde send tx input parameters
-    let mut data_vec_input:Vec<Token>=Vec::new();
-    data_vec_input.push(Token::Bytes(instance.0));
-    data_vec_input.push(Token::Address(prover));
-    data_vec_input.push(Token::Address(reward_token));
-    data_vec_input.push(Token::Uint(reward_amount));
-    data_vec_input.push(Token::Uint(liability_window.into()));
-    data_vec_input.push(Token::Address(liability_token));
-    data_vec_input.push(Token::Uint(liability_amount));
-    data_vec_input.push(Token::Uint(expiry.into()));
-    data_vec_input.push(Token::Bytes(signature.0));
-
-    let tx_data = func.encode_input(&data_vec_input).unwrap();
-
-    let priv_key = PRIV_KEY.lock().await;
-    let key = (*priv_key).clone();
-    let prvk = web3::signing::SecretKey::from_str(key.as_str()).unwrap();
-    let private_key = PrivateKey::from_str(key.as_str()).unwrap();
-    let addr = private_key.address();
+    let data_vec_input:Vec<Token>=vec![
+        Token::Bytes(instance.0),
+        Token::Address(prover),
+        Token::Address(reward_token),
+        Token::Uint(reward_amount),
+        Token::Uint(liability_window.into()),
+        Token::Address(liability_token),
+        Token::Uint(liability_amount),
+        Token::Uint(expiry.into()),
+        Token::Bytes(signature.0),
+    ];
+
+    func.encode_input(&data_vec_input).map_err(|e| format!("encode submitTask failed: {}", e))
+}
+
+/// submit proof data of task `task_key` to the chain of `net`
+pub async fn submit_task(net:&Network, task_key:&str, tx_data:Vec<u8>) -> Result<String, String> {
+    let web3 = net.client()?;
+
+    let key = PRIV_KEY.lock().await.clone();
+    let prvk = signing_key(&key)?;
+    let addr = web3::signing::SecretKeyRef::new(&prvk).address();
 
     let mut attempts = 0;
-    let mut gas_price = gas_price().await;
+    let mut fees = match gas_fees(net).await {
+        Ok(f) => f,
+        Err(e) => {
+            metrics::SUBMISSIONS.with_label_values(&["error"]).inc();
+            return Err(e);
+        }
+    };
     let mut gas_limit = U256::from_dec_str(GAS_UPPER).unwrap();
 
+    // one submission at a time; the node may not count our last tx as pending yet
+    let mut nonces = net.nonce.lock().await;
+
     //send tx to network
     loop {
-        let nonce = get_nonce(Address::from_str(addr.as_str()).unwrap()).await;
-        let tx_object = TransactionParameters {
-            to: Some(contract_address),
-            gas_price:Some(gas_price),
+        let pending = match get_nonce(net, addr).await {
+            Ok(n) => n,
+            Err(e) => {
+                metrics::SUBMISSIONS.with_label_values(&["error"]).inc();
+                return Err(e);
+            }
+        };
+        if nonces.next.is_some_and(|n| pending < n) && !any_tx_known(net, &web3, nonces.last_tx).await {
+            warn!("none of our unmined txs on {} are known to the node, continuing from its nonce {}", net.name, pending);
+            nonces.next = None;
+        }
+        let nonce = nonces.next.map_or(pending, |n| n.max(pending));
+        let mut tx_object = TransactionParameters {
+            to: Some(net.contract),
             gas:gas_limit,
             nonce:Some(nonce),
             data:Bytes(tx_data.clone()),
+            chain_id:Some(net.chain_id),
             ..Default::default()
         };
+        fees.apply(&mut tx_object);
 
         let signed = match web3.accounts().sign_transaction(tx_object.clone(), &prvk).await {
             Ok(signed_tx) => signed_tx,
             Err(e) => {
                 attempts += 1;
                 if attempts >= MAX_RETRIES {
+                    metrics::SUBMISSIONS.with_label_values(&["error"]).inc();
                     return Err(format!("Failed to sign transaction: {}", e));
                 }
+                metrics::SUBMIT_RETRIES.with_label_values(&["sign"]).inc();
                 time::sleep(Duration::from_secs(2u64.pow(attempts))).await;
                 continue;
             }
         };
 
+        // journaled first, a crash after the send must not lead to a second paid submission
+        queue::record_broadcast(task_key, &hex::encode(signed.transaction_hash.as_bytes())).await?;
+
+        metrics::GAS_PRICE.set(fees.max_price().low_u64() as i64);
         match web3.eth().send_raw_transaction(signed.raw_transaction).await {
             Ok(tx_hash) => {
                 info!("invoke a tx hash is : {:?}",tx_hash);
+                nonces.next = Some(nonce + 1);
+                nonces.last_tx = Some(tx_hash);
+                LAST_SUBMISSION.store(Utc::now().timestamp(), Ordering::SeqCst);
+                metrics::SUBMISSIONS.with_label_values(&["ok"]).inc();
                 return Ok(hex::encode(tx_hash.as_bytes()));
             },
             Err(e) => {
                 if e.to_string().contains("replacement transaction underpriced") {
-                    gas_price = gas_price * (100 + GAS_PRICE_INCREMENT_PERCENTAGE) / 100;
+                    fees = fees.bump(GAS_PRICE_INCREMENT_PERCENTAGE);
+                    metrics::SUBMIT_RETRIES.with_label_values(&["underpriced"]).inc();
+                } else if e.to_string().contains("nonce too low") {
+                    nonces.next = None;  //sent from elsewhere or our count is off, trust the node again
+                    metrics::SUBMIT_RETRIES.with_label_values(&["nonce"]).inc();
                 } else {
                     // Handle other errors or add a general error increment
                     gas_limit = gas_limit * (100 + GAS_INCREMENT_PERCENTAGE) / 100;
+                    metrics::SUBMIT_RETRIES.with_label_values(&["send"]).inc();
                 }
             }
         }
 
         attempts += 1;
         if attempts >= MAX_RETRIES {
+            nonces.next = None;
+            metrics::SUBMISSIONS.with_label_values(&["error"]).inc();
             return Err("Transaction failed after maximum number of retries".to_string());
         }
         time::sleep(Duration::from_secs(2u64.pow(attempts))).await;
     }
 }
 
+/// whether the node still knows any of our broadcast txs on `net`; lookup errors count as
+/// known, so a flaky node does not make us reuse nonces
+async fn any_tx_known(net: &Network, web3: &web3::Web3<MeteredHttp>, last_tx: Option<H256>) -> bool {
+    let mut hashes: Vec<H256> = last_tx.into_iter().collect();
+    for t in task::TASKS.lock().await.values() {
+        if t.state != TaskState::Broadcast || network::get(t.network.as_deref()).map_or(true, |n| n.name != net.name) {
+            continue
+        }
+        if let Some(h) = t.tx_hash.as_deref().and_then(|h| H256::from_str(h).ok()) {
+            hashes.push(h);
+        }
+    }
+    for h in hashes {
+        if !matches!(web3.eth().transaction(TransactionId::Hash(h)).await, Ok(None)) {
+            return true
+        }
+    }
+    false
+}
+
 pub async fn dummy_task() -> Result<String, String> {   //TBD
   info!("start to send dummy_task");
 
@@ -275,108 +574,331 @@ pub async fn dummy_task() -> Result<String, String> {   //TBD
   .duration_since(UNIX_EPOCH)
   .unwrap().as_millis(); 
 
-  let input = format!("{}{}{}","\"5.7,2.5,5,2\"".to_string(),"#".to_string(),random_number.to_string());
+  let input = format!("{}#{}","\"5.7,2.5,5,2\"",random_number);
 
-  let task_key_result = hex::encode(keccak256(&input.to_string().into_bytes()));
+  let task_key_result = task::task_key(&input);
   info!("this task task key is:{}",task_key_result);
-
-  match assign_task(input.to_string()).await{ //replace one task parameter String
+  let net = network::default_network();
+  let params = net.task_config(&config::current().task).defaults();
+  let mut record = Task::new(&task_key_result).with_params(params.clone());
+  record.network = Some(net.name.clone());
+  task::insert_task(record).await;
+  metrics::TASKS.with_label_values(&[TaskState::Received.as_str()]).inc();
+
+  match assign_task(&net, &task_key_result, input, &params).await{ //replace one task parameter String
     Ok(_) => {
         Ok("dummy task send success".to_string())
     },
-    Err(_) => {
+    Err(e) => {
         error!("dummy task generate failed");
+        task::fail(&task_key_result, &e).await;
         Err("dummy task send failed".to_string())
     },
 }
 }
 
-pub async fn assign_task(instance:String)  -> Result<(), String> {  //TBD
+pub async fn assign_task(net:&Network, task_key:&str, instance:String, params:&TaskParams)  -> Result<(), String> {  //TBD
+    if task::is_cancelled(task_key).await {
+        return Ok(())
+    }
+    task::transition(task_key, TaskState::Assigning, |_| ()).await?;
+
+    let task_response = request_assignment(net, instance, params).await?;
+    if task::is_cancelled(task_key).await {
+        info!("task {} was cancelled while waiting on the relayer", task_key);
+        return Ok(())
+    }
+    task::transition(task_key, TaskState::Assigned, |t| {
+        t.prover = Some(task_response.prover.clone());
+        t.expiry = Some(task_response.expiry);
+    }).await?;
+
+    let tx_data = submit_task_calldata(&task_response)?;
+    let expiry = task_response.expiry;
+
+    let now = Utc::now().timestamp() as u64;
+    if expiry > now + params.max_expiry {
+        return Err(format!("relayer expiry {} is beyond the task max_expiry of {}s", expiry, params.max_expiry))
+    }
+    if expiry <= now {
+        task::transition(task_key, TaskState::Expired, |t| t.error = Some("relayer assignment expired before broadcast".to_string())).await?;
+        return Ok(())
+    }
+
+    if !task::begin_submit(task_key).await? {
+        info!("task {} was cancelled before submission", task_key);
+        return Ok(())
+    }
+    //send onchain transcations
+    match submit_task(net, task_key, tx_data).await{
+        Ok(r) => {
+            info!("send submit_task success, tx hash is {:?}",r);
+            task::transition(task_key, TaskState::Broadcast, |t| {
+                t.tx_hash = Some(r);
+                t.submitting = false;
+            }).await?;
+            Ok(())
+        }
+        Err(e) => {
+            error!("send submit_task error, reason:{:?}",e);
+            task::update(task_key, |t| t.submitting = false).await?;
+            Err(format!("send submit_task error: {}", e))
+        },
+    }
+}
+
+/// ask the relayer to assign a prover to the task, the returned terms are checked against `params`
+pub async fn request_assignment(net:&Network, instance:String, params:&TaskParams) -> Result<TaskResponse, String> {
     let client = Client::new();
     let request = RpcRequest {
         jsonrpc: "2.0".to_string(),
         method: "ReceiveTask".to_string(),
-        params: vec![instance.to_string(), LIABILITY_WINDOW.to_string(),LIABILITY_TOKEN.to_string(),LIABILITY.to_string(),REWARD_TOKEN.to_string(),REWARD.to_string()],
+        params: vec![
+            instance,
+            params.liability_window.to_string(),
+            params.liability_token.clone(),
+            params.liability.to_string(),
+            params.reward_token.clone(),
+            params.reward.to_string(),
+        ],
         id: 1,
     };
 
-    let relayer_url = RELAYER_URL.lock().await;
-    let relayer_endpoint = (*relayer_url).clone();
-
-    // let response: RpcResponse = client
-    //     .post(relayer_endpoint.clone()) //relayer rpc address
-    //     .json(&request)
-    //     .send()
-    //     .await.unwrap()
-    //     .json()
-    //     .await.unwrap();
+    let relayer_endpoint = match &net.relayer {
+        Some(url) => url.clone(),
+        None => RELAYER_URL.lock().await.clone(),
+    };
 
+    let timer = metrics::RELAYER_LATENCY.start_timer();
     let response_res= match client
         .post(relayer_endpoint) //relayer rpc address
         .json(&request)
         .send()
         .await{
             Ok(r) => r,
-            Err(_) => return Err("invode relayer failed".to_string()),
+            Err(_) => {
+                metrics::RELAYER_ERRORS.inc();
+                return Err("invode relayer failed".to_string())
+            },
         };
     let response:RpcResponse=match response_res.json().await{
         Ok(r) => r,
-        Err(_) => return Err("invode relayer failed".to_string()),
+        Err(_) => {
+            metrics::RELAYER_ERRORS.inc();
+            return Err("invode relayer failed".to_string())
+        },
     };
+    timer.observe_duration();
 
     let task_response:TaskResponse=match serde_json::from_str(response.result.as_str()){
         Ok(r) => r,
         Err(_) => {
+            metrics::RELAYER_ERRORS.inc();
             info!("can not parse the relayer response:{:?}",response.result);
             return  Err("assign_task parse response error".to_string())
         },
     };
     info!("receice relayer response result is : {:?}", task_response); 
+    check_relayer_terms(&task_response, params)?;
+    Ok(task_response)
+}
 
-    let instance=Bytes::from(task_response.instance);
-    let addr=Address::from_str(task_response.prover.as_str()).unwrap();
-    let reward_token=Address::from_str(task_response.reward_token.as_str()).unwrap();
-    let reward = U256::from(task_response.reward);
-    let liability_window = u64::from(task_response.liability_window);
-    let liability_token=Address::from_str(task_response.liability_token.as_str()).unwrap();
-    let liability_amount = U256::from(task_response.liability);
-    let expiry = u64::from(task_response.expiry);
-
-    let sig_bytes = hex::decode(task_response.signature).unwrap();
-    let signature=Bytes::from(sig_bytes.clone());
-
-    info!("receive relayer response signature:{:?}",hex::encode(sig_bytes.clone()));
+/// the relayer signs the terms it returns, make sure they are the ones the client asked for
+fn check_relayer_terms(resp:&TaskResponse, params:&TaskParams) -> Result<(), String> {
+    if !resp.reward_token.eq_ignore_ascii_case(&params.reward_token)
+        || resp.reward != params.reward
+        || !resp.liability_token.eq_ignore_ascii_case(&params.liability_token)
+        || resp.liability != params.liability
+        || resp.liability_window != params.liability_window {
+        return Err("relayer returned different task terms than requested".to_string())
+    }
+    Ok(())
+}
 
-    // debug!("invoke the submitTask function parasmeter is:instance:{:?},prover:{:?},reward_token:{:?},reward:{:?},liability_window:{:?},liability_token:{:?},
-    // liability_amount:{:?},
-    // expiry:{:?},
-    // signature:{:?}",
-    // instance,addr,reward_token,reward,liability_window,liability_token,liability_amount,expiry,signature);
+/// what submitting the task would do, see `simulate_submission`
+#[derive(Debug, Serialize)]
+pub struct Simulation {
+    pub network: String,
+    pub chain_id: u64,
+    pub assignment: TaskResponse,
+    pub contract: String,
+    pub from: Option<String>,
+    /// hex submitTask calldata
+    pub calldata: String,
+    pub estimated_gas: Option<u64>,
+    /// fees submitTask would pay, max_fee and priority_fee of a type-2 tx or a legacy gas_price
+    pub max_fee: Option<String>,
+    pub priority_fee: Option<String>,
+    pub gas_price: Option<String>,
+    /// hex return data of the eth_call
+    pub call_result: Option<String>,
+    pub reverted: bool,
+    pub revert_reason: Option<String>,
+}
 
-    //send onchain transcations
-    let _ = match submit_task(instance,addr,reward_token,reward,liability_window,liability_token,liability_amount,expiry,signature).await{
-        Ok(r) => {
-            info!("send submit_task success, tx hash is {:?}",r)
+/// Error(string) selector of solidity reverts
+const REVERT_SELECTOR: [u8; 4] = [0x08, 0xc3, 0x79, 0xa0];
+
+/// the revert reason of a failed eth_call/eth_estimateGas, decoded from the error data when possible
+fn revert_reason(e: &jsonrpc_core::Error) -> String {
+    let data = e.data.as_ref()
+        .and_then(|d| d.as_str())
+        .and_then(|d| hex::decode(d.trim_start_matches("0x")).ok());
+    if let Some(data) = data {
+        if data.len() > 4 && data[..4] == REVERT_SELECTOR {
+            if let Ok(tokens) = web3::ethabi::decode(&[ParamType::String], &data[4..]) {
+                if let Some(Token::String(reason)) = tokens.into_iter().next() {
+                    return reason
+                }
+            }
         }
-        Err(e) => {
-            error!("send submit_task error, reason:{:?}",e)
+    }
+    e.message.clone()
+}
+
+/// dry run the submitTask call for an assignment: encode it, estimate its gas and eth_call it
+/// against the latest block, nothing is signed or broadcast
+pub async fn simulate_submission(net:&Network, resp:TaskResponse) -> Result<Simulation, String> {
+    let calldata = submit_task_calldata(&resp)?;
+    let contract = net.contract;
+    let from = signer_address().await.ok();
+    let web3 = net.client()?;
+
+    let request = CallRequest {
+        from,
+        to: Some(contract),
+        data: Some(Bytes(calldata.clone())),
+        ..Default::default()
+    };
+    let mut revert_reason_found = None;
+    let call_result = match web3.eth().call(request.clone(), None).await {
+        Ok(out) => Some(format!("0x{}", hex::encode(out.0))),
+        Err(web3::Error::Rpc(e)) => {
+            revert_reason_found = Some(revert_reason(&e));
+            None
         },
+        Err(e) => return Err(network::redact(format!("eth_call failed: {}", e))),
     };
-    Ok(())
+    let estimated_gas = match web3.eth().estimate_gas(request, None).await {
+        Ok(g) => Some(g.low_u64()),
+        Err(web3::Error::Rpc(e)) => {
+            revert_reason_found.get_or_insert_with(|| revert_reason(&e));
+            None
+        },
+        Err(e) => return Err(network::redact(format!("eth_estimateGas failed: {}", e))),
+    };
+    let (max_fee, priority_fee, gas_price) = match query_gas_fees(net).await {
+        Some(GasFees::Dynamic { max_fee, priority_fee }) => (Some(max_fee.to_string()), Some(priority_fee.to_string()), None),
+        Some(GasFees::Legacy { gas_price }) => (None, None, Some(gas_price.to_string())),
+        None => (None, None, None),
+    };
+
+    Ok(Simulation {
+        network: net.name.clone(),
+        chain_id: net.chain_id,
+        assignment: resp,
+        contract: format!("{:?}", contract),
+        from: from.map(|a| format!("{:?}", a)),
+        calldata: format!("0x{}", hex::encode(calldata)),
+        estimated_gas,
+        max_fee,
+        priority_fee,
+        gas_price,
+        call_result,
+        reverted: revert_reason_found.is_some(),
+        revert_reason: revert_reason_found,
+    })
 }
 
-pub async fn process_task_data(task:String){        //submit the task
-    match assign_task(task.clone()).await{
+pub async fn process_task_data(task_key:&str, task:String, params:&TaskParams, network:Option<&str>) -> Task {        //submit the task, return its record
+    let result = match network::get(network) {
+        Ok(net) => assign_task(&net, task_key, task.clone(), params).await,
+        Err(e) => Err(e),   //the network was removed from the config since the task was queued
+    };
+    match result {
         Ok(()) => (),
+        Err(r) if task::is_cancelled(task_key).await => {
+            info!("drop the cancelled task:{} ({})",task, r);
+        }
         Err(r) => {
-            error!("assign the task:{} failed {}",task, r)
+            error!("assign the task:{} failed {}",task, r);
+            task::fail(task_key, &r).await;
         }
     }
+    match task::get_task(task_key).await {
+        Some(t) => t,
+        None => Task::new(task_key),
+    }
 }
 
 
-pub fn test() {
-    println!("{}",Utc::now().timestamp());
-    let dt = (Utc::now() + chrono::Duration::from_std(Duration::from_secs(100)).unwrap()).timestamp();
-    println!("today date + 137 days {}", dt);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gwei(n: u64) -> U256 {
+        U256::from(n) * U256::exp10(9)
+    }
+
+    fn history(rewards: Vec<u64>) -> FeeHistory {
+        FeeHistory {
+            oldest_block: BlockNumber::Number(1.into()),
+            base_fee_per_gas: vec![gwei(1); rewards.len() + 1],
+            gas_used_ratio: vec![0.5; rewards.len()],
+            reward: Some(rewards.into_iter().map(|r| vec![gwei(r)]).collect()),
+        }
+    }
+
+    #[test]
+    fn priority_fee_is_the_median_tip_of_non_empty_blocks() {
+        assert_eq!(priority_fee(&history(vec![3, 0, 1, 2, 0])), gwei(2));
+        assert_eq!(priority_fee(&history(vec![4, 1])), gwei(4));
+        assert_eq!(priority_fee(&history(vec![5])), gwei(5));
+    }
+
+    #[test]
+    fn priority_fee_falls_back_without_tips() {
+        assert_eq!(priority_fee(&history(vec![0, 0])), U256::from(DEFAULT_PRIORITY_FEE));
+        let mut h = history(vec![]);
+        assert_eq!(priority_fee(&h), U256::from(DEFAULT_PRIORITY_FEE));
+        h.reward = None;
+        assert_eq!(priority_fee(&h), U256::from(DEFAULT_PRIORITY_FEE));
+    }
+
+    #[test]
+    fn bump_raises_every_fee() {
+        match (GasFees::Dynamic { max_fee: gwei(10), priority_fee: gwei(2) }).bump(20) {
+            GasFees::Dynamic { max_fee, priority_fee } => {
+                assert_eq!(max_fee, gwei(12));
+                assert_eq!(priority_fee, U256::from(2_400_000_000u64));
+            },
+            other => panic!("bumped into {:?}", other),
+        }
+        match (GasFees::Legacy { gas_price: gwei(5) }).bump(10) {
+            GasFees::Legacy { gas_price } => assert_eq!(gas_price, U256::from(5_500_000_000u64)),
+            other => panic!("bumped into {:?}", other),
+        }
+    }
+
+    #[test]
+    fn fees_set_the_matching_tx_fields() {
+        let mut tx = TransactionParameters::default();
+        (GasFees::Dynamic { max_fee: gwei(10), priority_fee: gwei(2) }).apply(&mut tx);
+        assert_eq!(tx.transaction_type, Some(U64::from(EIP1559_TX_TYPE)));
+        assert_eq!((tx.max_fee_per_gas, tx.max_priority_fee_per_gas), (Some(gwei(10)), Some(gwei(2))));
+        assert_eq!(tx.gas_price, None);
+
+        let mut tx = TransactionParameters::default();
+        (GasFees::Legacy { gas_price: gwei(5) }).apply(&mut tx);
+        assert_eq!((tx.transaction_type, tx.gas_price), (None, Some(gwei(5))));
+        assert_eq!(GasFees::Legacy { gas_price: gwei(5) }.max_price(), gwei(5));
+    }
+
+    #[test]
+    fn signing_key_accepts_an_optional_prefix() {
+        let key = "4c0883a69102937d6231471b5dbb6204fe5129617082792ae468d01a3f362318";
+        assert_eq!(signing_key(key).unwrap(), signing_key(&format!("0x{}", key)).unwrap());
+        assert!(signing_key("0xnotakey").is_err());
+    }
 }
@@ -0,0 +1,198 @@
+// Copyright (C) 2019-2022 Aleo Systems Inc.
+// This file is part of the aoraki-labs library.
+
+// The aoraki-labs library is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// The aoraki-labs library is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with the aoraki-labs library. If not, see <https://www.gnu.org/licenses/>.
+
+//! Settings loaded from the optional `--config` JSON file.
+//!
+//! Every section falls back to defaults matching the values the requestor used
+//! before the file existed, so an empty `{}` config behaves like no config.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::sync::RwLock;
+
+use lazy_static::lazy_static;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::auth::ApiKey;
+use crate::endpoint::ChainConfig;
+use crate::health::HealthConfig;
+use crate::metrics::MetricsConfig;
+use crate::network::NetworkConfig;
+use crate::queue::QueueConfig;
+use crate::quota::QuotaConfig;
+use crate::task::{TaskParams, TaskParamsRequest};
+use crate::worker::WorkerConfig;
+
+// Dev env
+//const REWARD_TOKEN:&str="0xfDfd239c9dD30445d0e080Ecf055A5cc53456A72";
+// Prod env
+const REWARD_TOKEN: &str = "0x0622118429C54577eF34229526661c41020048bF";
+
+lazy_static! {
+    pub static ref CONFIG: RwLock<Config> = {
+        RwLock::new(Config::default())
+    };
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub task: TaskConfig,
+    pub chain: ChainConfig,
+    /// network name -> network, a `sepolia` one from `chain` and the command line when empty
+    pub networks: BTreeMap<String, NetworkConfig>,
+    /// network of tasks that do not name one, required with several networks
+    pub default_network: Option<String>,
+    pub rpc: RpcConfig,
+    pub queue: QueueConfig,
+    pub auth: AuthConfig,
+    pub quota: QuotaConfig,
+    pub health: HealthConfig,
+    pub metrics: MetricsConfig,
+    pub worker: WorkerConfig,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AuthConfig {
+    /// the requestor refuses to start while this is empty, unless `disabled`
+    pub api_keys: Vec<ApiKey>,
+    /// let callers without a valid key in as submitters, admin methods still need an admin key
+    pub disabled: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RpcConfig {
+    /// most tasks accepted by one ReceiveTasks call
+    pub max_batch_size: usize,
+    /// how long a repeated idempotency key returns the original task; finished tasks
+    /// only survive a restart for a day, so longer windows only hold while running
+    pub idempotency_window_secs: u64,
+}
+
+impl Default for RpcConfig {
+    fn default() -> Self {
+        RpcConfig {
+            max_batch_size: 1000,
+            idempotency_window_secs: 24 * 3600,
+        }
+    }
+}
+
+/// defaults and accepted bounds for the economic terms of a task
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TaskConfig {
+    pub reward_token: String,
+    pub reward: u64,
+    pub liability_token: String,
+    pub liability: u64,
+    pub liability_window: u64,
+    pub max_expiry: u64,
+    /// tokens clients may choose for reward and liability
+    pub allowed_tokens: Vec<String>,
+    pub min_reward: u64,
+    pub max_reward: u64,
+    pub max_liability: u64,
+    pub min_liability_window: u64,
+    pub max_liability_window: u64,
+    pub max_max_expiry: u64,
+}
+
+impl Default for TaskConfig {
+    fn default() -> Self {
+        TaskConfig {
+            reward_token: REWARD_TOKEN.to_string(),
+            reward: 100,
+            liability_token: REWARD_TOKEN.to_string(),
+            liability: 100,
+            liability_window: 36000,
+            max_expiry: 36000,
+            allowed_tokens: vec![REWARD_TOKEN.to_string()],
+            min_reward: 1,
+            max_reward: 1000,
+            max_liability: 1000,
+            min_liability_window: 60,
+            max_liability_window: 7 * 24 * 3600,
+            max_max_expiry: 7 * 24 * 3600,
+        }
+    }
+}
+
+impl TaskConfig {
+    pub fn defaults(&self) -> TaskParams {
+        TaskParams {
+            reward_token: self.reward_token.clone(),
+            reward: self.reward,
+            liability_token: self.liability_token.clone(),
+            liability: self.liability,
+            liability_window: self.liability_window,
+            max_expiry: self.max_expiry,
+        }
+    }
+
+    /// fill the unset fields of a client request with defaults and check everything against the bounds
+    pub fn resolve(&self, req: &TaskParamsRequest) -> Result<TaskParams, String> {
+        let defaults = self.defaults();
+        let params = TaskParams {
+            reward_token: req.reward_token.clone().unwrap_or(defaults.reward_token),
+            reward: req.reward.unwrap_or(defaults.reward),
+            liability_token: req.liability_token.clone().unwrap_or(defaults.liability_token),
+            liability: req.liability.unwrap_or(defaults.liability),
+            liability_window: req.liability_window.unwrap_or(defaults.liability_window),
+            max_expiry: req.max_expiry.unwrap_or(defaults.max_expiry),
+        };
+
+        if !self.token_allowed(&params.reward_token) {
+            return Err(format!("reward_token {} is not allowed", params.reward_token));
+        }
+        if !self.token_allowed(&params.liability_token) {
+            return Err(format!("liability_token {} is not allowed", params.liability_token));
+        }
+        if params.reward < self.min_reward || params.reward > self.max_reward {
+            return Err(format!("reward must be within [{}, {}]", self.min_reward, self.max_reward));
+        }
+        if params.liability > self.max_liability {
+            return Err(format!("liability must not exceed {}", self.max_liability));
+        }
+        if params.liability_window < self.min_liability_window || params.liability_window > self.max_liability_window {
+            return Err(format!("liability_window must be within [{}, {}]", self.min_liability_window, self.max_liability_window));
+        }
+        if params.max_expiry == 0 || params.max_expiry > self.max_max_expiry {
+            return Err(format!("max_expiry must be within [1, {}]", self.max_max_expiry));
+        }
+        Ok(params)
+    }
+
+    fn token_allowed(&self, token: &str) -> bool {
+        self.allowed_tokens.iter().any(|t| t.eq_ignore_ascii_case(token))
+    }
+}
+
+pub fn load(path: &str) -> Result<Config, String> {
+    let content = fs::read_to_string(path).map_err(|e| format!("read config {} failed: {}", path, e))?;
+    serde_json::from_str(&content).map_err(|e| format!("parse config {} failed: {}", path, e))
+}
+
+/// a snapshot of the effective configuration
+pub fn current() -> Config {
+    CONFIG.read().unwrap().clone()
+}
+
+pub fn set(config: Config) {
+    *CONFIG.write().unwrap() = config;
+}
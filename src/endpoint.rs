@@ -0,0 +1,144 @@
+// Copyright (C) 2019-2022 Aleo Systems Inc.
+// This file is part of the aoraki-labs library.
+
+// The aoraki-labs library is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// The aoraki-labs library is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with the aoraki-labs library. If not, see <https://www.gnu.org/licenses/>.
+
+//! Chain node rpc endpoints of a network.
+//!
+//! `${VAR}` in a url is replaced with the environment variable `VAR`, so api
+//! keys stay out of the files and the process arguments.
+
+use rand::distributions::{Distribution, WeightedIndex};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::admin::redact_url;
+
+const DEFAULT_URL: &str = "https://eth-sepolia.g.alchemy.com/v2/${ALCHEMY_API_KEY}";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EndpointConfig {
+    pub url: String,
+    /// share of the calls spread over the endpoints, 0 only serves as a fallback
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+/// endpoints of the network built when the config file has no `networks`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ChainConfig {
+    pub endpoints: Vec<EndpointConfig>,
+}
+
+impl Default for ChainConfig {
+    fn default() -> Self {
+        ChainConfig {
+            endpoints: vec![EndpointConfig { url: DEFAULT_URL.to_string(), weight: default_weight() }],
+        }
+    }
+}
+
+/// an endpoint with its env vars expanded
+pub struct Endpoint {
+    url: String,
+    weight: u32,
+}
+
+/// `url` or `url#weight` as given on the command line
+pub fn parse_arg(arg: &str) -> Result<EndpointConfig, String> {
+    let arg = arg.trim();
+    match arg.rsplit_once('#') {
+        Some((url, weight)) => {
+            let weight = weight.parse().map_err(|_| format!("invalid weight {} for chain url {}", weight, redact_url(url)))?;
+            Ok(EndpointConfig { url: url.to_string(), weight })
+        },
+        None => Ok(EndpointConfig { url: arg.to_string(), weight: default_weight() }),
+    }
+}
+
+/// replace every `${VAR}` with the value of the environment variable
+fn expand_env(url: &str) -> Result<String, String> {
+    let mut expanded = String::with_capacity(url.len());
+    let mut rest = url;
+    while let Some(start) = rest.find("${") {
+        let end = rest[start..].find('}').ok_or_else(|| format!("unterminated ${{ in chain url {}", redact_url(url)))?;
+        let name = &rest[start + 2..start + end];
+        let value = std::env::var(name).map_err(|_| format!("chain url {} needs the environment variable {}", redact_url(url), name))?;
+        expanded.push_str(&rest[..start]);
+        expanded.push_str(&value);
+        rest = &rest[start + end + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+/// check the endpoints and expand their env vars
+pub fn resolve(endpoints: &[EndpointConfig]) -> Result<Vec<Endpoint>, String> {
+    if endpoints.is_empty() {
+        return Err("no chain endpoint configured".to_string())
+    }
+    if endpoints.iter().all(|e| e.weight == 0) {
+        return Err("at least one chain endpoint needs a positive weight".to_string())
+    }
+    let mut resolved = Vec::with_capacity(endpoints.len());
+    for e in endpoints.iter() {
+        let url = expand_env(&e.url)?;
+        if !url.starts_with("http://") && !url.starts_with("https://") {
+            return Err(format!("chain url {} is not an http(s) url", redact_url(&url)))
+        }
+        resolved.push(Endpoint { url, weight: e.weight });
+    }
+    Ok(resolved)
+}
+
+/// every endpoint in the configured order, for calls that try them one by one
+pub fn urls(endpoints: &[Endpoint]) -> Vec<String> {
+    endpoints.iter().map(|e| e.url.clone()).collect()
+}
+
+/// one endpoint chosen at random by weight
+pub fn pick(endpoints: &[Endpoint]) -> Result<String, String> {
+    let index = WeightedIndex::new(endpoints.iter().map(|e| e.weight))
+        .map_err(|e| format!("no usable chain endpoint: {}", e))?;
+    Ok(endpoints[index.sample(&mut rand::thread_rng())].url.clone())
+}
+
+/// every form an endpoint url may take in an error, with its redacted replacement
+pub fn redactions(endpoints: &[Endpoint]) -> Vec<(String, String)> {
+    let mut forms: Vec<(String, String)> = Vec::new();
+    for ep in endpoints.iter() {
+        let redacted = redact_url(&ep.url);
+        forms.push((ep.url.clone(), redacted.clone()));
+        // http clients report the url normalized and without its user info
+        if let Ok(mut parsed) = reqwest::Url::parse(&ep.url) {
+            forms.push((parsed.to_string(), redacted.clone()));
+            let _ = parsed.set_username("");
+            let _ = parsed.set_password(None);
+            forms.push((parsed.to_string(), redacted));
+        }
+    }
+    forms
+}
+
+/// the endpoints with their urls redacted, for operators
+pub fn describe(endpoints: &[Endpoint]) -> Vec<Value> {
+    endpoints.iter()
+        .map(|e| json!({ "url": redact_url(&e.url), "weight": e.weight }))
+        .collect()
+}
@@ -0,0 +1,112 @@
+// Copyright (C) 2019-2022 Aleo Systems Inc.
+// This file is part of the aoraki-labs library.
+
+// The aoraki-labs library is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// The aoraki-labs library is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with the aoraki-labs library. If not, see <https://www.gnu.org/licenses/>.
+
+//! Error codes returned by the requestor RPC methods.
+//!
+//! Standard JSON-RPC codes are reused where they fit; everything specific to the
+//! requestor lives in the implementation-defined -32000..-32099 range. The `data`
+//! member is always an object with at least a `reason` string.
+
+use jsonrpc_http_server::jsonrpc_core::{Error, ErrorCode as RpcErrorCode, Value};
+use serde_json::json;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    InvalidParams,
+    Internal,
+    TaskNotFound,
+    QueueFull,
+    Unauthorized,
+    Forbidden,
+    RateLimited,
+    QuotaExceeded,
+    TaskNotCancellable,
+    IngestionPaused,
+    RelayerUnavailable,
+    ChainUnavailable,
+    QueueUnavailable,
+}
+
+impl ErrorCode {
+    pub fn code(&self) -> i64 {
+        match self {
+            ErrorCode::InvalidParams => -32602,
+            ErrorCode::Internal => -32603,
+            ErrorCode::TaskNotFound => -32001,
+            ErrorCode::QueueFull => -32002,
+            ErrorCode::Unauthorized => -32003,
+            ErrorCode::Forbidden => -32004,
+            ErrorCode::RateLimited => -32005,
+            ErrorCode::QuotaExceeded => -32006,
+            ErrorCode::TaskNotCancellable => -32007,
+            ErrorCode::IngestionPaused => -32008,
+            ErrorCode::RelayerUnavailable => -32010,
+            ErrorCode::ChainUnavailable => -32011,
+            ErrorCode::QueueUnavailable => -32012,
+        }
+    }
+
+    pub fn message(&self) -> &'static str {
+        match self {
+            ErrorCode::InvalidParams => "Invalid params",
+            ErrorCode::Internal => "Internal error",
+            ErrorCode::TaskNotFound => "Task not found",
+            ErrorCode::QueueFull => "Task queue full",
+            ErrorCode::Unauthorized => "Unauthorized",
+            ErrorCode::Forbidden => "Forbidden",
+            ErrorCode::RateLimited => "Rate limited",
+            ErrorCode::QuotaExceeded => "Quota exceeded",
+            ErrorCode::TaskNotCancellable => "Task can not be cancelled",
+            ErrorCode::IngestionPaused => "Task ingestion paused",
+            ErrorCode::RelayerUnavailable => "Relayer unavailable",
+            ErrorCode::ChainUnavailable => "Chain unavailable",
+            ErrorCode::QueueUnavailable => "Task queue unavailable",
+        }
+    }
+}
+
+/// build a JSON-RPC error; `data` is merged into `{"reason": ...}`
+pub fn rpc_error(code: ErrorCode, reason: &str, data: Option<Value>) -> Error {
+    let mut body = json!({ "reason": reason });
+    if let (Some(Value::Object(extra)), Value::Object(map)) = (data, &mut body) {
+        map.extend(extra);
+    }
+    Error {
+        code: RpcErrorCode::from(code.code()),
+        message: code.message().to_string(),
+        data: Some(body),
+    }
+}
+
+/// the `reason` of an error built by `rpc_error`, its message otherwise
+pub fn reason(err: &Error) -> String {
+    err.data.as_ref()
+        .and_then(|d| d["reason"].as_str())
+        .unwrap_or(&err.message)
+        .to_string()
+}
+
+pub fn invalid_params(reason: &str) -> Error {
+    rpc_error(ErrorCode::InvalidParams, reason, None)
+}
+
+/// add one more member to the `data` object of an error
+pub fn with_data(mut err: Error, key: &str, value: Value) -> Error {
+    if let Some(Value::Object(map)) = err.data.as_mut() {
+        map.insert(key.to_string(), value);
+    }
+    err
+}
@@ -0,0 +1,304 @@
+// Copyright (C) 2019-2022 Aleo Systems Inc.
+// This file is part of the aoraki-labs library.
+
+// The aoraki-labs library is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// The aoraki-labs library is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with the aoraki-labs library. If not, see <https://www.gnu.org/licenses/>.
+
+//! `GET /healthz` and `GET /readyz` on the RPC server.
+//!
+//! The dependency checks run in the background with a timeout each, the
+//! endpoints only serve the last report so they answer even when a dependency
+//! hangs. `/healthz` fails when the checks stopped running, `/readyz` also
+//! when any check fails.
+
+use std::collections::BTreeMap;
+use std::sync::atomic::Ordering;
+use std::sync::RwLock;
+use std::time::Instant;
+
+use chrono::Utc;
+use jsonrpc_http_server::hyper::{self, Body, Method, Response, StatusCode};
+use jsonrpc_http_server::jsonrpc_core::futures::future;
+use lazy_static::lazy_static;
+use log::*;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::time::{self, Duration};
+use web3::types::{BlockId, BlockNumber, U256};
+
+use crate::admin;
+use crate::chain::{self, LAST_SUBMISSION, RELAYER_URL};
+use crate::config;
+use crate::network::{self, Network};
+use crate::queue::TASK_QUEUE;
+use crate::shutdown;
+
+const CHECK_TIMEOUT_SECS: u64 = 5;
+
+lazy_static! {
+    static ref REPORT: RwLock<Option<Report>> = {   //last result of the dependency checks
+        RwLock::new(None)
+    };
+    static ref STARTED_AT: i64 = Utc::now().timestamp();
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HealthConfig {
+    pub check_interval_secs: u64,
+    /// the latest block must be younger than this
+    pub max_block_age_secs: u64,
+    pub min_balance_wei: u64,
+    /// with tasks queued, the last submission must be younger than this; 0 only reports it
+    pub max_submission_age_secs: u64,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        HealthConfig {
+            check_interval_secs: 15,
+            max_block_age_secs: 120,
+            min_balance_wei: 10_000_000_000_000_000,  //0.01 ETH
+            max_submission_age_secs: 0,
+        }
+    }
+}
+
+struct Report {
+    at: Instant,
+    failed: Vec<String>,
+    body: Value,
+}
+
+fn check(ok: bool, details: Value) -> Value {
+    let mut c = json!({ "ok": ok });
+    if let (Some(c), Value::Object(d)) = (c.as_object_mut(), details) {
+        c.extend(d);
+    }
+    c
+}
+
+/// run a check with the timeout; errors are served unauthenticated so the node urls are redacted
+async fn with_timeout<F: std::future::Future<Output = Result<Value, String>>>(f: F) -> Result<Value, String> {
+    match time::timeout(Duration::from_secs(CHECK_TIMEOUT_SECS), f).await {
+        Ok(Ok(v)) => Ok(v),
+        Ok(Err(e)) => Err(network::redact(e)),
+        Err(_) => Err(format!("no answer within {}s", CHECK_TIMEOUT_SECS)),
+    }
+}
+
+/// any http answer short of a server error counts as reachable
+async fn check_relayer(url: &str) -> Value {
+    let result = with_timeout(async {
+        let resp = reqwest::Client::new()
+            .post(url)
+            .json(&json!({ "jsonrpc": "2.0", "method": "ping", "params": [], "id": 1 }))
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+        if resp.status().is_server_error() {
+            return Err(format!("relayer answered {}", resp.status()))
+        }
+        Ok(json!({}))
+    }).await;
+    match result {
+        Ok(_) => check(true, json!({})),
+        Err(e) => check(false, json!({ "error": e })),
+    }
+}
+
+/// every distinct relayer in use, `--relayer` for the networks without their own
+async fn check_relayers() -> Value {
+    let default = RELAYER_URL.lock().await.clone();
+    let mut relayers: BTreeMap<String, Vec<String>> = BTreeMap::new();   //url -> networks using it
+    for net in network::all() {
+        relayers.entry(net.relayer.clone().unwrap_or_else(|| default.clone())).or_default().push(net.name.clone());
+    }
+    let checks = future::join_all(relayers.keys().map(|url| check_relayer(url))).await;
+    let all_ok = checks.iter().all(|c| c["ok"] == Value::Bool(true));
+    let mut details = serde_json::Map::new();
+    for ((url, networks), mut c) in relayers.into_iter().zip(checks) {
+        c["networks"] = json!(networks);
+        details.insert(admin::redact_url(&url), c);
+    }
+    check(all_ok, json!({ "relayers": details }))
+}
+
+/// the node must be on the configured chain and its latest block recent
+async fn check_network_chain(net: &Network, cfg: &HealthConfig) -> Value {
+    let result = with_timeout(async {
+        let web3 = net.client()?;
+        let chain_id = web3.eth().chain_id().await.map_err(|e| e.to_string())?;
+        if chain_id != U256::from(net.chain_id) {
+            return Err(format!("the node is on chain {} instead of {}", chain_id, net.chain_id))
+        }
+        let block = web3.eth().block(BlockId::Number(BlockNumber::Latest)).await
+            .map_err(|e| e.to_string())?
+            .ok_or("the node returned no latest block")?;
+        let age = Utc::now().timestamp() - block.timestamp.as_u64() as i64;
+        Ok(json!({
+            "chain_id": net.chain_id,
+            "block_number": block.number.map(|n| n.as_u64()),
+            "block_age_secs": age,
+        }))
+    }).await;
+    match result {
+        Ok(d) => {
+            let fresh = d["block_age_secs"].as_i64().is_some_and(|age| age <= cfg.max_block_age_secs as i64);
+            check(fresh, d)
+        },
+        Err(e) => check(false, json!({ "error": e })),
+    }
+}
+
+async fn check_network_balance(net: &Network, cfg: &HealthConfig) -> Value {
+    let result = with_timeout(async {
+        let addr = chain::signer_address().await?;
+        let web3 = net.client()?;
+        let balance = web3.eth().balance(addr, None).await.map_err(|e| e.to_string())?;
+        Ok(json!({
+            "address": format!("{:?}", addr),
+            "balance_wei": balance.to_string(),
+        }))
+    }).await;
+    match result {
+        Ok(mut d) => {
+            let balance = d["balance_wei"].as_str().and_then(|b| U256::from_dec_str(b).ok()).unwrap_or_default();
+            d["min_balance_wei"] = json!(cfg.min_balance_wei);
+            check(balance >= U256::from(cfg.min_balance_wei), d)
+        },
+        Err(e) => check(false, json!({ "error": e })),
+    }
+}
+
+/// chain and balance checks of every network, each ok when all networks are
+async fn check_networks(cfg: &HealthConfig) -> (Value, Value) {
+    let (mut chains, mut balances) = (serde_json::Map::new(), serde_json::Map::new());
+    for net in network::all() {
+        let (chain, balance) = tokio::join!(check_network_chain(&net, cfg), check_network_balance(&net, cfg));
+        chains.insert(net.name.clone(), chain);
+        balances.insert(net.name.clone(), balance);
+    }
+    let all_ok = |m: &serde_json::Map<String, Value>| m.values().all(|c| c["ok"] == Value::Bool(true));
+    (
+        check(all_ok(&chains), json!({ "networks": chains })),
+        check(all_ok(&balances), json!({ "networks": balances })),
+    )
+}
+
+async fn check_queue(cfg: &HealthConfig) -> (Value, Value) {
+    let (depth, delayed) = {
+        let queue = TASK_QUEUE.lock().await;
+        (queue.len(), queue.delayed())
+    };
+    // a full queue rejects new tasks, so the requestor is not ready for more
+    let max_depth = config::current().queue.max_depth;
+    let queue = check(max_depth == 0 || depth < max_depth, json!({
+        "depth": depth,
+        "delayed": delayed,
+        "max_depth": max_depth,
+        "ingestion_paused": admin::ingestion_paused(),
+        "processing_paused": admin::processing_paused(),
+    }));
+
+    let now = Utc::now().timestamp();
+    let last = LAST_SUBMISSION.load(Ordering::SeqCst);
+    let since = if last > 0 { last } else { *STARTED_AT };
+    let stale = cfg.max_submission_age_secs > 0 && depth > delayed && now - since > cfg.max_submission_age_secs as i64;
+    let submission = check(!stale, json!({
+        "at": if last > 0 { json!(last) } else { Value::Null },
+        "age_secs": if last > 0 { json!(now - last) } else { Value::Null },
+    }));
+    (queue, submission)
+}
+
+async fn run_checks() {
+    let cfg = config::current().health;
+    let (relayer, (chain, balance), (queue, submission)) = tokio::join!(
+        check_relayers(),
+        check_networks(&cfg),
+        check_queue(&cfg),
+    );
+    let ingestion = check(!admin::ingestion_paused() && !shutdown::requested(), json!({
+        "shutting_down": shutdown::requested(),
+    }));
+    let checks = json!({
+        "relayer": relayer,
+        "chain": chain,
+        "balance": balance,
+        "queue": queue,
+        "last_submission": submission,
+        "ingestion": ingestion,
+    });
+    let failed: Vec<String> = checks.as_object().unwrap().iter()
+        .filter(|(_, c)| c["ok"] != Value::Bool(true))
+        .map(|(name, _)| name.clone())
+        .collect();
+    let previous = REPORT.read().unwrap().as_ref().map(|r| r.failed.clone()).unwrap_or_default();
+    if failed != previous {
+        if failed.is_empty() {
+            info!("health checks passing again");
+        } else {
+            warn!("health checks failing: {}", failed.join(", "));
+        }
+    }
+    let body = json!({
+        "status": if failed.is_empty() { "ok" } else { "degraded" },
+        "checked_at": Utc::now().timestamp(),
+        "started_at": *STARTED_AT,
+        "checks": checks,
+    });
+    *REPORT.write().unwrap() = Some(Report { at: Instant::now(), failed, body });
+}
+
+pub async fn health_check_loop() {
+    lazy_static::initialize(&STARTED_AT);
+    loop {
+        run_checks().await;
+        time::sleep(Duration::from_secs(config::current().health.check_interval_secs.max(1))).await;
+    }
+}
+
+fn respond(status: StatusCode, body: Value) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(hyper::header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+/// answer the health endpoints, `None` for every other request
+pub fn route(req: &hyper::Request<Body>) -> Option<Response<Body>> {
+    if req.method() != Method::GET {
+        return None
+    }
+    let readiness = match req.uri().path() {
+        "/healthz" => false,
+        "/readyz" => true,
+        _ => return None,
+    };
+
+    let interval = config::current().health.check_interval_secs.max(1);
+    let report = REPORT.read().unwrap();
+    let response = match report.as_ref() {
+        None if readiness => respond(StatusCode::SERVICE_UNAVAILABLE, json!({ "status": "starting" })),
+        None => respond(StatusCode::OK, json!({ "status": "starting" })),
+        // the checks themselves time out, so a report this old means the runtime is stuck
+        Some(r) if r.at.elapsed().as_secs() > 3 * (interval + CHECK_TIMEOUT_SECS) => {
+            respond(StatusCode::SERVICE_UNAVAILABLE, json!({ "status": "stalled", "last_report": r.body }))
+        },
+        Some(r) if readiness && !r.failed.is_empty() => respond(StatusCode::SERVICE_UNAVAILABLE, r.body.clone()),
+        Some(r) => respond(StatusCode::OK, r.body.clone()),
+    };
+    Some(response)
+}
@@ -0,0 +1,96 @@
+// Copyright (C) 2019-2022 Aleo Systems Inc.
+// This file is part of the aoraki-labs library.
+
+// The aoraki-labs library is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// The aoraki-labs library is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with the aoraki-labs library. If not, see <https://www.gnu.org/licenses/>.
+
+//! env_logger output with a filter that can be replaced at runtime.
+//!
+//! The filter uses the `RUST_LOG` syntax, e.g. `debug` or
+//! `info,zkpool_demo_requestor::chain=debug`.
+
+use std::str::FromStr;
+use std::sync::RwLock;
+
+use env_logger::filter::{self, Filter};
+use lazy_static::lazy_static;
+use log::{LevelFilter, Log, Metadata, Record};
+
+const DEFAULT_FILTER: &str = "info";
+
+lazy_static! {
+    static ref FILTER: RwLock<(String, Filter)> = {   //filter spec -> parsed filter
+        RwLock::new((DEFAULT_FILTER.to_string(), build_filter(DEFAULT_FILTER)))
+    };
+}
+
+struct Logger {
+    inner: env_logger::Logger,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        FILTER.read().unwrap().1.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if FILTER.read().unwrap().1.matches(record) {
+            self.inner.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+fn build_filter(spec: &str) -> Filter {
+    filter::Builder::new()
+        .parse(spec)
+        .filter(Some("chain"), LevelFilter::Error)
+        .build()
+}
+
+/// install the logger with the `RUST_LOG` filter, `info` when unset
+pub fn init() {
+    let spec = std::env::var("RUST_LOG").unwrap_or_else(|_| DEFAULT_FILTER.to_string());
+    // the inner logger only formats, filtering is done here
+    let inner = env_logger::Builder::new().filter_level(LevelFilter::Trace).build();
+    log::set_boxed_logger(Box::new(Logger { inner })).expect("the logger is installed once");
+    if let Err(e) = set_filter(&spec) {
+        eprintln!("ignore RUST_LOG: {}", e);
+        log::set_max_level(FILTER.read().unwrap().1.filter());
+    }
+}
+
+/// replace the filter, rejecting specs with unknown levels
+pub fn set_filter(spec: &str) -> Result<(), String> {
+    let spec = spec.trim();
+    if spec.is_empty() {
+        return Err("empty log filter".to_string());
+    }
+    for directive in spec.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+        let level = directive.rsplit_once('=').map_or(directive, |(_, l)| l);
+        if LevelFilter::from_str(level).is_err() && directive.contains('=') {
+            return Err(format!("unknown log level {} in {}", level, directive));
+        }
+    }
+    let filter = build_filter(spec);
+    log::set_max_level(filter.filter());
+    *FILTER.write().unwrap() = (spec.to_string(), filter);
+    Ok(())
+}
+
+pub fn current_filter() -> String {
+    FILTER.read().unwrap().0.clone()
+}
@@ -14,36 +14,53 @@
 // You should have received a copy of the GNU General Public License
 // along with the aoraki-labs library. If not, see <https://www.gnu.org/licenses/>.
 
-use std::{thread};
 use tokio::time::{self, Duration};
 
-use chain::dummy_task;
+use chain::{dummy_task, watch_receipts};
 use log::*;
 use clap::{load_yaml, App};
-use server::loop_task_data;
-use web3::signing::keccak256;
 
 mod server;
 mod chain;
-use crate::{server::start_rpc_server, chain::{PRIV_KEY, RELAYER_URL, CONTRACT}};
+mod task;
+mod queue;
+mod error;
+mod config;
+mod pubsub;
+mod auth;
+mod quota;
+mod admin;
+mod logger;
+mod health;
+mod metrics;
+mod endpoint;
+mod network;
+mod shutdown;
+mod worker;
+use crate::{server::start_rpc_server, chain::{PRIV_KEY, RELAYER_URL}};
 
 #[macro_use]
 mod app_marco;
 
-use std::time::{Instant};
+const RECEIPT_POLL_INTERVAL: u64 = 15;
 
 pub async fn main_process_task_data() {
     std::panic::set_hook(Box::new(|panic_info| {
         error!("Panic occurred: {:?}", panic_info);
     }));
 
+    worker::run().await;
+}
+
+pub async fn receipt_watch_loop(confirmations:u64) { //follow broadcast txs until they are settled
     loop{
-        time::sleep(Duration::from_secs(1)).await;
-        match loop_task_data().await{
-            Ok(()) => (),
-            Err(_) => {
-                error!("***process one proof task error***");
-            }
+        time::sleep(Duration::from_secs(RECEIPT_POLL_INTERVAL)).await;
+        if let Err(e) = watch_receipts(confirmations).await {
+            error!("watch tx receipts failed: {}", e);
+        }
+        let evicted = task::evict_finished(queue::ACK_RETENTION_SECS).await;
+        if evicted > 0 {
+            info!("forgot {} finished tasks past the retention", evicted);
         }
     }
 }
@@ -51,6 +68,13 @@ pub async fn main_process_task_data() {
 pub async fn dummy_task_loop(interval:u64) { //dummy onchain task in interval seconds period
     loop{
         time::sleep(Duration::from_secs(interval)).await;
+        if shutdown::requested() {
+            break;
+        }
+        if admin::dummy_paused() {
+            continue;
+        }
+        let _work = shutdown::begin_work();
         let mut retry:usize = 0;
         loop{
             retry += 1;
@@ -71,54 +95,139 @@ pub async fn dummy_task_loop(interval:u64) { //dummy onchain task in interval se
 #[tokio::main]
 async fn main() {
 
-    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info"))
-    .filter(Some("chain"), log::LevelFilter::Error)
-    .init();
+    logger::init();
 
     let cli_param_yml = load_yaml!("app.yml");
     let cli_param = App::from_yaml(cli_param_yml).get_matches();
+    if let Some(api_key) = cli_param.value_of("hash-key") {
+        println!("{}", auth::hash_key(api_key));
+        return;
+    }
     let key: String = cli_param.value_of("key").unwrap_or("").into();
     let listen: String = cli_param.value_of("listen").unwrap_or("").into();
+    let ws_listen: String = cli_param.value_of("ws").unwrap_or("").into();
     let relayer: String = cli_param.value_of("relayer").unwrap_or("").into();
     let interval: String = cli_param.value_of("interval").unwrap_or("").into();
     let contract_addr: String = cli_param.value_of("contract").unwrap_or("").into();
+    let queue_path: String = cli_param.value_of("queue").unwrap_or("").into();
+    let confirmations: String = cli_param.value_of("confirmations").unwrap_or("").into();
+    let shutdown_timeout: String = cli_param.value_of("shutdown-timeout").unwrap_or("").into();
+
+    admin::set_cli_settings(serde_json::json!({
+        "listen": listen,
+        "ws": ws_listen,
+        "relayer": relayer,
+        "interval": interval,
+        "contract": contract_addr,
+        "queue": queue_path,
+        "confirmations": confirmations,
+        "shutdown_timeout": shutdown_timeout,
+        "config": cli_param.value_of("config"),
+        "chain_url": cli_param.values_of("chain-url").map(|v| v.map(admin::redact_url).collect::<Vec<_>>()),
+    }).as_object().cloned().unwrap_or_default());
+
+    if let Some(path) = cli_param.value_of("config") {
+        match config::load(path) {
+            Ok(c) => config::set(c),
+            Err(e) => {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    }
     
+    // the command line only overrides the default network, and its contract only when given
+    let cfg = config::current();
+    let contract = if cfg.networks.is_empty() || cli_param.occurrences_of("contract") > 0 {
+        Some(contract_addr.clone())
+    } else {
+        None
+    };
+    let networks = match cli_param.values_of("chain-url") {
+        Some(urls) => urls.map(endpoint::parse_arg).collect::<Result<Vec<_>, String>>().map(Some),
+        None => Ok(None),
+    }.and_then(|endpoints| network::init(&cfg, endpoints, contract));
+    if let Err(e) = networks {
+        error!("{}", e);
+        std::process::exit(1);
+    }
+
     {
         let mut priv_key = PRIV_KEY.lock().await;
-        *priv_key=key.clone();
+        *priv_key=key;
     
         let mut relayer_url = RELAYER_URL.lock().await;
-        *relayer_url=relayer.clone();
+        *relayer_url=relayer;
+
+    }
 
-        let mut contract = CONTRACT.lock().await;
-        *contract=contract_addr.clone();
+    metrics::register();
 
+    // anyone reaching the rpc servers could spend our rewards and gas, fail closed
+    let auth = config::current().auth;
+    if auth.disabled {
+        warn!("authentication is disabled, the rpc servers accept every caller as a submitter");
+    } else if auth.api_keys.is_empty() {
+        error!("no api keys configured in the auth section, set auth.disabled to run without authentication");
+        std::process::exit(1);
+    }
+
+    match queue::open_queue(&queue_path).await {
+        Ok(n) => info!("restored {} queued tasks from {}", n, queue_path),
+        Err(e) => {
+            error!("open the task queue failed: {}", e);
+            std::process::exit(1);
+        }
     }
 
     let my_server = start_rpc_server(listen);
-    let srv_handle = tokio::spawn(async move {
-        my_server.await.wait();
-    });
-    
-    let process_task_handle = tokio::spawn(async move {
-        main_process_task_data().await
+    let srv_handle = tokio::task::spawn_blocking(move || {
+        my_server.wait();
     });
 
-    let dummy_task_handle = tokio::spawn(async move {
-        dummy_task_loop(interval.parse::<u64>().unwrap()).await
+    let ws_server = pubsub::start_ws_server(ws_listen);
+    let ws_handle = tokio::task::spawn_blocking(move || {
+        let _ = ws_server.wait();
     });
+    
+    let process_task_handle = tokio::spawn(main_process_task_data());
+
+    let dummy_task_handle = tokio::spawn(dummy_task_loop(interval.parse::<u64>().unwrap()));
 
+    let receipt_handle = tokio::spawn(receipt_watch_loop(confirmations.parse::<u64>().unwrap()));
 
+    let health_handle = tokio::spawn(health::health_check_loop());
+
+    let shutdown_timeout = Duration::from_secs(shutdown_timeout.parse::<u64>().unwrap());
  
-    tokio::select! {
-      _ = async { srv_handle.await } => {
-        info!("server terminal")
+    let status = tokio::select! {
+      _ = shutdown::signal() => {
+        shutdown::graceful(shutdown_timeout).await
+        },
+      _ = srv_handle => {
+        error!("server terminal");
+        shutdown::graceful(shutdown_timeout).await.max(1)
+        },
+      _ = ws_handle => {
+        error!("websocket server terminal");
+        shutdown::graceful(shutdown_timeout).await.max(1)
         },
-      _ = async { process_task_handle.await } => {
-        info!("process task handle terminal")
+      _ = process_task_handle => {
+        error!("process task handle terminal");
+        shutdown::graceful(shutdown_timeout).await.max(1)
         },
-      _ = async { dummy_task_handle.await } => {
-        info!("dummy task handle terminal")
+      _ = dummy_task_handle => {
+        error!("dummy task handle terminal");
+        shutdown::graceful(shutdown_timeout).await.max(1)
        },
-    }
+      _ = receipt_handle => {
+        error!("receipt watch handle terminal");
+        shutdown::graceful(shutdown_timeout).await.max(1)
+       },
+      _ = health_handle => {
+        error!("health check handle terminal");
+        shutdown::graceful(shutdown_timeout).await.max(1)
+       },
+    };
+    std::process::exit(status);
 }
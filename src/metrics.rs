@@ -0,0 +1,178 @@
+// Copyright (C) 2019-2022 Aleo Systems Inc.
+// This file is part of the aoraki-labs library.
+
+// The aoraki-labs library is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// The aoraki-labs library is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with the aoraki-labs library. If not, see <https://www.gnu.org/licenses/>.
+
+//! Prometheus metrics, served in the text format on the RPC server at
+//! `metrics.path` (`/metrics` by default).
+
+use std::future::Future;
+use std::pin::Pin;
+use std::time::Instant;
+
+use jsonrpc_http_server::hyper::{self, Body, Method, Response, StatusCode};
+use jsonrpc_http_server::jsonrpc_core::Call;
+use lazy_static::lazy_static;
+use prometheus::{
+    register_counter_vec, register_histogram, register_histogram_vec, register_int_counter,
+    register_int_counter_vec, register_int_gauge, CounterVec, Encoder, Histogram, HistogramVec,
+    IntCounter, IntCounterVec, IntGauge, TextEncoder,
+};
+use serde_derive::{Deserialize, Serialize};
+use web3::transports::Http;
+use web3::{RequestId, Transport};
+
+use crate::admin;
+use crate::config;
+
+lazy_static! {
+    pub static ref TASKS: IntCounterVec = register_int_counter_vec!(
+        "requestor_tasks_total", "Tasks that entered each state", &["state"]
+    ).unwrap();
+    pub static ref QUEUE_DEPTH: IntGauge = register_int_gauge!(
+        "requestor_queue_depth", "Tasks waiting in the queue"
+    ).unwrap();
+    pub static ref QUEUE_MAX_DEPTH: IntGauge = register_int_gauge!(
+        "requestor_queue_max_depth", "Queue depth above which new tasks are rejected, 0 is unbounded"
+    ).unwrap();
+    pub static ref QUEUE_ABOVE_HIGH_WATER: IntGauge = register_int_gauge!(
+        "requestor_queue_above_high_water", "1 while the queue depth is at or above its high-water mark"
+    ).unwrap();
+    pub static ref QUEUE_REJECTIONS: IntCounter = register_int_counter!(
+        "requestor_queue_full_rejections_total", "ReceiveTask/ReceiveTasks calls rejected because the queue was full"
+    ).unwrap();
+    pub static ref RELAYER_LATENCY: Histogram = register_histogram!(
+        "requestor_relayer_request_duration_seconds", "Duration of relayer ReceiveTask calls"
+    ).unwrap();
+    pub static ref RELAYER_ERRORS: IntCounter = register_int_counter!(
+        "requestor_relayer_errors_total", "Relayer calls that failed or returned an unusable answer"
+    ).unwrap();
+    static ref CHAIN_LATENCY: HistogramVec = register_histogram_vec!(
+        "requestor_chain_request_duration_seconds", "Duration of chain node rpc calls", &["endpoint", "method"]
+    ).unwrap();
+    static ref CHAIN_ERRORS: IntCounterVec = register_int_counter_vec!(
+        "requestor_chain_errors_total", "Chain node rpc calls that failed", &["endpoint", "method"]
+    ).unwrap();
+    pub static ref GAS_PRICE: IntGauge = register_int_gauge!(
+        "requestor_gas_price_wei", "Max fee per gas, or legacy gas price, of the last submitTask tx sent"
+    ).unwrap();
+    pub static ref GAS_USED: CounterVec = register_counter_vec!(
+        "requestor_gas_used_total", "Gas used by mined submitTask txs", &["status"]
+    ).unwrap();
+    pub static ref SUBMISSIONS: IntCounterVec = register_int_counter_vec!(
+        "requestor_submissions_total", "submitTask txs by outcome", &["result"]
+    ).unwrap();
+    pub static ref SUBMIT_RETRIES: IntCounterVec = register_int_counter_vec!(
+        "requestor_submit_retries_total", "submitTask send retries by cause", &["reason"]
+    ).unwrap();
+}
+
+/// register every metric up front so scrapes see them before their first use
+pub fn register() {
+    lazy_static::initialize(&TASKS);
+    lazy_static::initialize(&QUEUE_DEPTH);
+    lazy_static::initialize(&QUEUE_MAX_DEPTH);
+    lazy_static::initialize(&QUEUE_ABOVE_HIGH_WATER);
+    lazy_static::initialize(&QUEUE_REJECTIONS);
+    lazy_static::initialize(&RELAYER_LATENCY);
+    lazy_static::initialize(&RELAYER_ERRORS);
+    lazy_static::initialize(&CHAIN_LATENCY);
+    lazy_static::initialize(&CHAIN_ERRORS);
+    lazy_static::initialize(&GAS_PRICE);
+    lazy_static::initialize(&GAS_USED);
+    lazy_static::initialize(&SUBMISSIONS);
+    lazy_static::initialize(&SUBMIT_RETRIES);
+    QUEUE_MAX_DEPTH.set(config::current().queue.max_depth as i64);
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MetricsConfig {
+    pub enabled: bool,
+    pub path: String,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        MetricsConfig {
+            enabled: true,
+            path: "/metrics".to_string(),
+        }
+    }
+}
+
+/// http transport recording the latency and errors of every call per endpoint
+#[derive(Debug, Clone)]
+pub struct MeteredHttp {
+    inner: Http,
+    endpoint: String,
+}
+
+impl MeteredHttp {
+    pub fn new(url: &str) -> web3::Result<Self> {
+        Ok(MeteredHttp {
+            inner: Http::new(url)?,
+            // node urls carry api keys, label by host only
+            endpoint: admin::redact_url(url),
+        })
+    }
+}
+
+type CallFuture = Pin<Box<dyn Future<Output = web3::Result<serde_json::Value>> + Send>>;
+
+impl Transport for MeteredHttp {
+    type Out = CallFuture;
+
+    fn prepare(&self, method: &str, params: Vec<serde_json::Value>) -> (RequestId, Call) {
+        self.inner.prepare(method, params)
+    }
+
+    fn send(&self, id: RequestId, request: Call) -> Self::Out {
+        let method = match &request {
+            Call::MethodCall(m) => m.method.clone(),
+            _ => "unknown".to_string(),
+        };
+        let endpoint = self.endpoint.clone();
+        let call = self.inner.send(id, request);
+        Box::pin(async move {
+            let started = Instant::now();
+            let result = call.await;
+            CHAIN_LATENCY.with_label_values(&[&endpoint, &method]).observe(started.elapsed().as_secs_f64());
+            if result.is_err() {
+                CHAIN_ERRORS.with_label_values(&[&endpoint, &method]).inc();
+            }
+            result
+        })
+    }
+}
+
+/// answer scrapes of the metrics path, `None` for every other request
+pub fn route(req: &hyper::Request<Body>) -> Option<Response<Body>> {
+    let cfg = config::current().metrics;
+    if !cfg.enabled || req.method() != Method::GET || req.uri().path() != cfg.path {
+        return None
+    }
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    let response = match encoder.encode(&prometheus::gather(), &mut buffer) {
+        Ok(()) => Response::builder()
+            .status(StatusCode::OK)
+            .header(hyper::header::CONTENT_TYPE, encoder.format_type())
+            .body(Body::from(buffer)),
+        Err(e) => Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(Body::from(e.to_string())),
+    };
+    Some(response.unwrap())
+}
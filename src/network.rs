@@ -0,0 +1,224 @@
+// Copyright (C) 2019-2022 Aleo Systems Inc.
+// This file is part of the aoraki-labs library.
+
+// The aoraki-labs library is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// The aoraki-labs library is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with the aoraki-labs library. If not, see <https://www.gnu.org/licenses/>.
+
+//! Chain networks tasks are submitted to.
+//!
+//! Every network has its own EIP-155 chain id, node endpoints, contract and
+//! task tokens, set in the `networks` section of the config file. Without that
+//! section a single `sepolia` network is built from `--chain-url` (or the
+//! `chain` section), `--contracts` and the `task` tokens. A task names its
+//! network with `network`, `default_network` applies otherwise.
+
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+
+use lazy_static::lazy_static;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use web3::types::{Address, H256, U256};
+
+use crate::config::{Config, TaskConfig};
+use crate::endpoint::{self, Endpoint, EndpointConfig};
+use crate::metrics::MeteredHttp;
+
+pub const SEPOLIA_CHAIN_ID: u64 = 11155111;
+const SEPOLIA: &str = "sepolia";
+
+lazy_static! {
+    static ref NETWORKS: RwLock<BTreeMap<String, Arc<Network>>> = {   //network name -> network
+        RwLock::new(BTreeMap::new())
+    };
+    static ref DEFAULT_NETWORK: RwLock<String> = {
+        RwLock::new(SEPOLIA.to_string())
+    };
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NetworkConfig {
+    /// EIP-155 chain id the transactions are signed for
+    pub chain_id: u64,
+    pub endpoints: Vec<EndpointConfig>,
+    /// ZKPool contract address
+    pub contract: String,
+    /// relayer rpc url for this network, `--relayer` when unset
+    #[serde(default)]
+    pub relayer: Option<String>,
+    /// default tokens of this network, the `task` ones when unset
+    #[serde(default)]
+    pub reward_token: Option<String>,
+    #[serde(default)]
+    pub liability_token: Option<String>,
+    /// tokens clients may choose on this network, the `task` ones when empty
+    #[serde(default)]
+    pub allowed_tokens: Vec<String>,
+}
+
+/// our view of the signer's nonce on a network
+#[derive(Debug, Default)]
+pub struct NonceState {
+    /// nonce after our last sent tx
+    pub next: Option<U256>,
+    /// hash of our last sent tx, its task may not be marked broadcast yet
+    pub last_tx: Option<H256>,
+}
+
+pub struct Network {
+    pub name: String,
+    pub chain_id: u64,
+    endpoints: Vec<Endpoint>,
+    pub contract: Address,
+    pub relayer: Option<String>,
+    reward_token: Option<String>,
+    liability_token: Option<String>,
+    allowed_tokens: Vec<String>,
+    /// held while submitting so txs go out in nonce order
+    pub nonce: tokio::sync::Mutex<NonceState>,
+}
+
+impl Network {
+    fn new(name: &str, cfg: &NetworkConfig) -> Result<Self, String> {
+        let contract = Address::from_str(cfg.contract.trim_start_matches("0x"))
+            .map_err(|e| format!("invalid contract address {} of network {}: {}", cfg.contract, name, e))?;
+        let endpoints = endpoint::resolve(&cfg.endpoints).map_err(|e| format!("network {}: {}", name, e))?;
+        Ok(Network {
+            name: name.to_string(),
+            chain_id: cfg.chain_id,
+            endpoints,
+            contract,
+            relayer: cfg.relayer.clone(),
+            reward_token: cfg.reward_token.clone(),
+            liability_token: cfg.liability_token.clone(),
+            allowed_tokens: cfg.allowed_tokens.clone(),
+            nonce: tokio::sync::Mutex::new(NonceState::default()),
+        })
+    }
+
+    /// every endpoint in the configured order
+    pub fn urls(&self) -> Vec<String> {
+        endpoint::urls(&self.endpoints)
+    }
+
+    /// a client on one endpoint chosen by weight
+    pub fn client(&self) -> Result<web3::Web3<MeteredHttp>, String> {
+        let url = endpoint::pick(&self.endpoints)?;
+        let transport = MeteredHttp::new(&url).map_err(|e| redact(e.to_string()))?;
+        Ok(web3::Web3::new(transport))
+    }
+
+    /// trust the node's pending nonce again, e.g. once one of our txs was dropped
+    pub async fn forget_nonce(&self) {
+        self.nonce.lock().await.next = None;
+    }
+
+    /// the task defaults and bounds with this network's tokens
+    pub fn task_config(&self, base: &TaskConfig) -> TaskConfig {
+        let mut cfg = base.clone();
+        if let Some(t) = &self.reward_token {
+            cfg.reward_token = t.clone();
+        }
+        if let Some(t) = &self.liability_token {
+            cfg.liability_token = t.clone();
+        }
+        if !self.allowed_tokens.is_empty() {
+            cfg.allowed_tokens = self.allowed_tokens.clone();
+        }
+        cfg
+    }
+
+    pub fn describe(&self) -> Value {
+        json!({
+            "chain_id": self.chain_id,
+            "contract": format!("{:?}", self.contract),
+            "endpoints": endpoint::describe(&self.endpoints),
+            "relayer": self.relayer,
+        })
+    }
+}
+
+/// build the networks from the config file and the command line overrides, which only
+/// apply to the default network
+pub fn init(cfg: &Config, endpoints: Option<Vec<EndpointConfig>>, contract: Option<String>) -> Result<(), String> {
+    let mut configs = cfg.networks.clone();
+    let default = match (&cfg.default_network, configs.len()) {
+        (Some(name), _) => name.clone(),
+        (None, 0) => SEPOLIA.to_string(),
+        (None, 1) => configs.keys().next().unwrap().clone(),
+        (None, _) => return Err("default_network must be set when several networks are configured".to_string()),
+    };
+    if configs.is_empty() {
+        configs.insert(SEPOLIA.to_string(), NetworkConfig {
+            chain_id: SEPOLIA_CHAIN_ID,
+            endpoints: cfg.chain.endpoints.clone(),
+            contract: String::new(),
+            relayer: None,
+            reward_token: None,
+            liability_token: None,
+            allowed_tokens: Vec::new(),
+        });
+    }
+    let default_cfg = configs.get_mut(&default).ok_or_else(|| format!("default_network {} is not configured", default))?;
+    if let Some(e) = endpoints {
+        default_cfg.endpoints = e;
+    }
+    if let Some(c) = contract {
+        default_cfg.contract = c;
+    }
+
+    let mut networks = BTreeMap::new();
+    for (name, c) in configs.iter() {
+        networks.insert(name.clone(), Arc::new(Network::new(name, c)?));
+    }
+    *NETWORKS.write().unwrap() = networks;
+    *DEFAULT_NETWORK.write().unwrap() = default;
+    Ok(())
+}
+
+/// the named network, the default one for `None`
+pub fn get(name: Option<&str>) -> Result<Arc<Network>, String> {
+    let name = match name {
+        Some(n) => n.to_string(),
+        None => DEFAULT_NETWORK.read().unwrap().clone(),
+    };
+    NETWORKS.read().unwrap().get(&name).cloned().ok_or_else(|| format!("unknown network {}", name))
+}
+
+pub fn default_network() -> Arc<Network> {
+    get(None).expect("the default network is configured")
+}
+
+pub fn all() -> Vec<Arc<Network>> {
+    NETWORKS.read().unwrap().values().cloned().collect()
+}
+
+/// node urls carry api keys, strip them from errors that leave the process
+pub fn redact(e: String) -> String {
+    let mut forms: Vec<(String, String)> = NETWORKS.read().unwrap().values()
+        .flat_map(|n| endpoint::redactions(&n.endpoints))
+        .collect();
+    // longest first so a url is not partly replaced through a shorter one it starts with
+    forms.sort_by_key(|f| std::cmp::Reverse(f.0.len()));
+    forms.iter().fold(e, |e, (url, redacted)| e.replace(url, redacted))
+}
+
+/// every network with its endpoints redacted, for operators
+pub fn describe() -> Value {
+    let mut networks = serde_json::Map::new();
+    for n in all() {
+        networks.insert(n.name.clone(), n.describe());
+    }
+    json!({ "default": *DEFAULT_NETWORK.read().unwrap(), "networks": networks })
+}
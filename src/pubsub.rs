@@ -0,0 +1,188 @@
+// Copyright (C) 2019-2022 Aleo Systems Inc.
+// This file is part of the aoraki-labs library.
+
+// The aoraki-labs library is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// The aoraki-labs library is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with the aoraki-labs library. If not, see <https://www.gnu.org/licenses/>.
+
+//! WebSocket server pushing task lifecycle changes to subscribers.
+//!
+//! Every change published on `task::TASK_EVENTS` is forwarded as a `taskUpdate`
+//! notification `{"subscription": id, "result": <task record>}`.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use jsonrpc_pubsub::{PubSubHandler, Session, Sink, Subscriber, SubscriptionId};
+use jsonrpc_ws_server::jsonrpc_core::futures::future;
+use jsonrpc_ws_server::jsonrpc_core::{MetaIoHandler, Params, Value};
+use jsonrpc_ws_server::{ws, RequestContext, ServerBuilder};
+use lazy_static::lazy_static;
+use log::*;
+use serde_json::json;
+use tokio::runtime::Handle;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::broadcast::Receiver;
+use tokio::task::JoinHandle;
+
+use crate::auth::{self, Role};
+use crate::error::{invalid_params, rpc_error, ErrorCode};
+use crate::task::{self, Task, TaskFilter, TASK_EVENTS};
+
+lazy_static! {
+    static ref SUBSCRIPTIONS: Mutex<HashMap<String, JoinHandle<()>>> = {   //subscription id -> forwarding task
+        Mutex::new(HashMap::new())
+    };
+}
+
+pub fn start_ws_server(addr: String) -> jsonrpc_ws_server::Server {
+    let mut io = PubSubHandler::new(MetaIoHandler::default());
+
+    let handle = Handle::current();
+    io.add_subscription(
+        "taskUpdate",
+        ("subscribeTask", move |params: Params, meta: Arc<Session>, subscriber: Subscriber| {
+            let key = match params.parse::<Vec<String>>() {
+                Ok(p) if p.len() == 1 => p[0].trim_start_matches("0x").to_string(),
+                _ => {
+                    let _ = subscriber.reject(invalid_params("expected [task_key]"));
+                    return
+                },
+            };
+            handle.spawn(async move {
+                let events = TASK_EVENTS.subscribe();
+                let current = match task::get_task(&key).await {
+                    Some(t) => t,
+                    None => {
+                        let _ = subscriber.reject(rpc_error(ErrorCode::TaskNotFound, "no task with this key", Some(json!({ "task_key": key }))));
+                        return
+                    },
+                };
+                let (id, sink) = match assign(subscriber, &meta).await {
+                    Some(s) => s,
+                    None => return,
+                };
+                // the current record first; events were subscribed before the lookup so nothing is missed
+                if notify(&sink, &id, &current).is_err() {
+                    return
+                }
+                forward(id, sink, events, move |t| t.task_key == key);
+            });
+        }),
+        ("unsubscribeTask", |id: SubscriptionId, _meta: Option<Arc<Session>>| future::ready(unsubscribe(id))),
+    );
+
+    let handle = Handle::current();
+    io.add_subscription(
+        "taskUpdate",
+        ("subscribeAllTasks", move |params: Params, meta: Arc<Session>, subscriber: Subscriber| {
+            let filter = match params {
+                Params::None => TaskFilter::default(),
+                Params::Array(a) if a.is_empty() => TaskFilter::default(),
+                Params::Array(mut a) if a.len() == 1 => match serde_json::from_value(a.remove(0)) {
+                    Ok(f) => f,
+                    Err(e) => {
+                        let _ = subscriber.reject(invalid_params(&format!("invalid filter: {}", e)));
+                        return
+                    },
+                },
+                _ => {
+                    let _ = subscriber.reject(invalid_params("expected [filter]"));
+                    return
+                },
+            };
+            let events = TASK_EVENTS.subscribe();
+            handle.spawn(async move {
+                let (id, sink) = match assign(subscriber, &meta).await {
+                    Some(s) => s,
+                    None => return,
+                };
+                forward(id, sink, events, move |t| filter.matches(t));
+            });
+        }),
+        ("unsubscribeAllTasks", |id: SubscriptionId, _meta: Option<Arc<Session>>| future::ready(unsubscribe(id))),
+    );
+
+    info!("start the websocket server on :{}", addr);
+
+    ServerBuilder::with_meta_extractor(io, |context: &RequestContext| Arc::new(Session::new(context.sender())))
+        .request_middleware(authorize_handshake)
+        .start(&addr.parse().unwrap())
+        .unwrap()
+}
+
+/// subscriptions are read-only, so the handshake needs at least a reader key
+fn authorize_handshake(req: &ws::Request) -> Option<ws::Response> {
+    let caller = auth::authenticate(req.header("authorization").and_then(|h| auth::bearer_token(h)));
+    match caller.role {
+        None => Some(ws::Response::new(401, "Unauthorized", b"missing or unknown api key".to_vec())),
+        Some(role) if role < Role::Reader => Some(ws::Response::new(403, "Forbidden", Vec::new())),
+        Some(_) => None,
+    }
+}
+
+/// hand the client its subscription id, resolves once the id was sent so no notification can overtake it
+async fn assign(subscriber: Subscriber, meta: &Arc<Session>) -> Option<(String, Sink)> {
+    let id = format!("0x{}", hex::encode(rand::random::<[u8; 16]>()));
+    let sink = subscriber.assign_id_async(SubscriptionId::String(id.clone())).await.ok()?;
+    let dropped = id.clone();
+    meta.on_drop(move || {
+        if let Some(h) = SUBSCRIPTIONS.lock().unwrap().remove(&dropped) {
+            h.abort();
+        }
+    });
+    Some((id, sink))
+}
+
+fn notify(sink: &Sink, id: &str, t: &Task) -> Result<(), ()> {
+    let result = serde_json::to_value(t).map_err(|_| ())?;
+    let mut params = serde_json::Map::new();
+    params.insert("subscription".to_string(), Value::String(id.to_string()));
+    params.insert("result".to_string(), result);
+    sink.notify(Params::Map(params)).map_err(|_| ())
+}
+
+/// push every matching task event to the sink until the client goes away or unsubscribes
+fn forward<F: Fn(&Task) -> bool + Send + 'static>(id: String, sink: Sink, mut events: Receiver<Task>, wanted: F) {
+    // hold the registry while spawning so the task can not deregister before it is registered
+    let mut subscriptions = SUBSCRIPTIONS.lock().unwrap();
+    let sub_id = id.clone();
+    let h = tokio::spawn(async move {
+        loop {
+            match events.recv().await {
+                Ok(t) => {
+                    if wanted(&t) && notify(&sink, &sub_id, &t).is_err() {
+                        break
+                    }
+                },
+                Err(RecvError::Lagged(n)) => warn!("subscription {} missed {} task events", sub_id, n),
+                Err(RecvError::Closed) => break,
+            }
+        }
+        SUBSCRIPTIONS.lock().unwrap().remove(&sub_id);
+    });
+    subscriptions.insert(id, h);
+}
+
+fn unsubscribe(id: SubscriptionId) -> jsonrpc_ws_server::jsonrpc_core::Result<Value> {
+    let key = match id {
+        SubscriptionId::String(s) => s,
+        SubscriptionId::Number(n) => n.to_string(),
+    };
+    match SUBSCRIPTIONS.lock().unwrap().remove(&key) {
+        Some(h) => {
+            h.abort();
+            Ok(Value::Bool(true))
+        },
+        None => Err(invalid_params("unknown subscription id")),
+    }
+}
@@ -0,0 +1,742 @@
+// Copyright (C) 2019-2022 Aleo Systems Inc.
+// This file is part of the aoraki-labs library.
+
+// The aoraki-labs library is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// The aoraki-labs library is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with the aoraki-labs library. If not, see <https://www.gnu.org/licenses/>.
+
+//! Crash-safe task queue.
+//!
+//! Every accepted task is appended to a write-ahead journal (one JSON record per
+//! line, fsync'd before the caller gets its task key) and only leaves the queue
+//! once an `ack` record carrying its outcome has been written. The signed hash of
+//! its `submitTask` tx is journaled before the tx is sent, so a task that may
+//! have reached the chain is followed as broadcast after a crash instead of
+//! being submitted twice. Replaying the journal on start-up rebuilds both the
+//! pending queue and the task table.
+
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+
+use chrono::Utc;
+use lazy_static::lazy_static;
+use log::*;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::config;
+use crate::metrics;
+use crate::task::{self, Priority, Task, TaskParams, TaskState};
+
+const COMPACT_THRESHOLD: usize = 1000;  //rewrite the journal after this many appended records
+pub const ACK_RETENTION_SECS: i64 = 24 * 3600;  //keep finished tasks in the journal for task lookups
+const DRAIN_RATE_WINDOW_SECS: i64 = 300;  //acks in this window estimate how fast the queue drains
+const MIN_DRAIN_RATE_SPAN_SECS: i64 = 10;
+const DEFAULT_RETRY_AFTER_SECS: u64 = 60;  //retry hint while nothing drained recently
+const MAX_RETRY_AFTER_SECS: u64 = 3600;
+
+lazy_static! {
+    pub static ref TASK_QUEUE: tokio::sync::Mutex<TaskQueue> = {
+        tokio::sync::Mutex::new(TaskQueue::default())
+    };
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct QueueConfig {
+    /// ReceiveTask/ReceiveTasks are rejected while they would queue more tasks than this, 0 is unbounded
+    pub max_depth: usize,
+    /// warn once the queue holds this many tasks, 0 never warns
+    pub high_water_mark: usize,
+    /// every this many seconds waited moves a queued task up one priority class, 0 never does
+    pub aging_secs: u64,
+    /// how far ahead a task's `not_before` may be
+    pub max_delay_secs: u64,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        QueueConfig {
+            max_depth: 10000,
+            high_water_mark: 8000,
+            aging_secs: 300,
+            max_delay_secs: 7 * 24 * 3600,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QueuedTask {
+    pub id: u64,
+    pub key: String,
+    pub input: String,
+    #[serde(default)]
+    pub params: Option<TaskParams>,
+    #[serde(default)]
+    pub client: Option<String>,
+    #[serde(default)]
+    pub idempotency_key: Option<String>,
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    #[serde(default)]
+    pub priority: Priority,
+    #[serde(default)]
+    pub deadline: Option<i64>,
+    #[serde(default)]
+    pub not_before: Option<i64>,
+    #[serde(default)]
+    pub network: Option<String>,
+    pub received_at: i64,
+}
+
+impl QueuedTask {
+    pub fn is_due(&self, now: i64) -> bool {
+        self.not_before.is_none_or(|at| at <= now)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct AckedTask {
+    task: QueuedTask,
+    outcome: Task,
+    acked_at: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum JournalRecord {
+    Push { task: QueuedTask },
+    PushBatch { tasks: Vec<QueuedTask> },  //one line, so a batch is journaled all or nothing
+    Ack { id: u64, outcome: Task, acked_at: i64 },
+    Update { outcome: Task },
+    Broadcasting { id: u64, outcome: Task },  //written before the tx is sent, `outcome` is the task once broadcast
+}
+
+#[derive(Default)]
+pub struct TaskQueue {
+    path: Option<PathBuf>,
+    journal: Option<File>,
+    pending: VecDeque<QueuedTask>,
+    acked: VecDeque<AckedTask>,
+    broadcasting: HashMap<u64, Task>,  //queued task id -> its record once the tx being sent is out
+    next_id: u64,
+    appended: usize,
+    above_high_water: bool,
+}
+
+impl TaskQueue {
+    /// replay the journal at `path` and keep it open for appending
+    pub fn open(&mut self, path: &str) -> Result<(), String> {
+        let path = PathBuf::from(path);
+        let mut pending: Vec<QueuedTask> = Vec::new();
+        let mut acked: Vec<AckedTask> = Vec::new();
+        let mut broadcasting: HashMap<u64, Task> = HashMap::new();
+        let mut next_id = 0;
+
+        if path.exists() {
+            let file = File::open(&path).map_err(|e| format!("open journal {:?} failed: {}", path, e))?;
+            let lines = BufReader::new(file).lines().collect::<Result<Vec<String>, _>>()
+                .map_err(|e| format!("read journal {:?} failed: {}", path, e))?;
+            let last = lines.iter().rposition(|l| !l.trim().is_empty());
+            for (n, line) in lines.iter().enumerate() {
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let record: JournalRecord = match serde_json::from_str(line) {
+                    Ok(r) => r,
+                    Err(e) if Some(n) == last => {
+                        // a torn final write from a crash; everything before it is intact
+                        warn!("skip unreadable last journal record at line {}: {}", n + 1, e);
+                        continue;
+                    }
+                    // compacting would drop the record for good, leave the journal to the operator
+                    Err(e) => return Err(format!("corrupt journal {:?} at line {}: {}", path, n + 1, e)),
+                };
+                match record {
+                    JournalRecord::Push { task } => {
+                        next_id = next_id.max(task.id + 1);
+                        pending.push(task);
+                    }
+                    JournalRecord::PushBatch { tasks } => {
+                        for task in tasks {
+                            next_id = next_id.max(task.id + 1);
+                            pending.push(task);
+                        }
+                    }
+                    JournalRecord::Ack { id, outcome, acked_at } => {
+                        broadcasting.remove(&id);
+                        if let Some(pos) = pending.iter().position(|t| t.id == id) {
+                            let task = pending.remove(pos);
+                            acked.push(AckedTask { task, outcome, acked_at });
+                        }
+                    }
+                    JournalRecord::Update { outcome } => {
+                        if let Some(a) = acked.iter_mut().find(|a| a.task.key == outcome.task_key) {
+                            a.outcome = outcome;
+                        }
+                    }
+                    JournalRecord::Broadcasting { id, outcome } => {
+                        if pending.iter().any(|t| t.id == id) {
+                            broadcasting.insert(id, outcome);
+                        }
+                    }
+                }
+            }
+        }
+
+        // the tx may be on chain though its worker never recorded the outcome, follow its
+        // receipt rather than submitting the task again
+        let now = Utc::now().timestamp();
+        pending.retain(|t| match broadcasting.remove(&t.id) {
+            Some(outcome) => {
+                warn!("task {} was being broadcast at the last stop, following tx {:?}", t.key, outcome.tx_hash);
+                acked.push(AckedTask { task: t.clone(), outcome, acked_at: now });
+                false
+            },
+            None => true,
+        });
+
+        self.path = Some(path);
+        self.pending = pending.into();
+        self.depth_changed();
+        self.acked = acked.into();
+        self.next_id = next_id;
+        self.compact()?;
+        info!("task queue opened with {} pending tasks", self.pending.len());
+        Ok(())
+    }
+
+    /// durably append tasks as one unit; they are only queued once the journal write
+    /// succeeded, and either all of them are or none. Ids and receive time are assigned here
+    pub fn push_many(&mut self, mut tasks: Vec<QueuedTask>) -> Result<Vec<QueuedTask>, String> {
+        let received_at = Utc::now().timestamp();
+        for (i, t) in tasks.iter_mut().enumerate() {
+            t.id = self.next_id + i as u64;
+            t.received_at = received_at;
+        }
+        let record = if tasks.len() == 1 {
+            JournalRecord::Push { task: tasks[0].clone() }
+        } else {
+            JournalRecord::PushBatch { tasks: tasks.clone() }
+        };
+        self.append(&record)?;
+        self.next_id += tasks.len() as u64;
+        self.pending.extend(tasks.iter().cloned());
+        self.depth_changed();
+        Ok(tasks)
+    }
+
+    /// the next due task to process, skipping the ones already handed to a worker: the highest
+    /// priority class after aging, then the earliest deadline, then the longest waiting
+    pub fn next_ready(&self, skip: &HashSet<u64>) -> Option<&QueuedTask> {
+        let aging_secs = config::current().queue.aging_secs;
+        let now = Utc::now().timestamp();
+        self.pending.iter()
+            .filter(|t| !skip.contains(&t.id) && t.is_due(now))
+            .min_by_key(|t| {
+                let since = t.not_before.map_or(t.received_at, |at| at.max(t.received_at));  //delayed tasks age once due
+                let waited = (now - since).max(0) as u64;
+                let promoted = waited.checked_div(aging_secs).unwrap_or(0);
+                (t.priority.rank().saturating_sub(promoted), t.deadline.unwrap_or(i64::MAX), t.id)
+            })
+    }
+
+    /// queue id of a pending task
+    pub fn queued_id(&self, key: &str) -> Option<u64> {
+        self.pending.iter().find(|t| t.key == key).map(|t| t.id)
+    }
+
+    pub fn is_queued(&self, id: u64) -> bool {
+        self.pending.iter().any(|t| t.id == id)
+    }
+
+    /// record the outcome of a task and drop it from the queue
+    pub fn ack(&mut self, id: u64, outcome: Task) -> Result<(), String> {
+        let pos = match self.pending.iter().position(|t| t.id == id) {
+            Some(p) => p,
+            None => return Err(format!("task {} is not queued", id)),
+        };
+        let acked_at = Utc::now().timestamp();
+        self.append(&JournalRecord::Ack { id, outcome: outcome.clone(), acked_at })?;
+        self.broadcasting.remove(&id);
+        let task = self.pending.remove(pos).unwrap();
+        self.depth_changed();
+        self.acked.push_back(AckedTask { task, outcome, acked_at });
+        if self.appended >= COMPACT_THRESHOLD {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    /// record the tx about to be sent for a queued task, `outcome` being its record once
+    /// broadcast; a task that is not queued (e.g. the dummy task) is left alone
+    pub fn broadcasting(&mut self, outcome: Task) -> Result<(), String> {
+        let id = match self.queued_id(&outcome.task_key) {
+            Some(id) => id,
+            None => return Ok(()),
+        };
+        self.append(&JournalRecord::Broadcasting { id, outcome: outcome.clone() })?;
+        self.broadcasting.insert(id, outcome);
+        Ok(())
+    }
+
+    /// the record journaled for a queued task whose tx is being sent, if any
+    pub fn broadcast_outcome(&self, id: u64) -> Option<&Task> {
+        self.broadcasting.get(&id)
+    }
+
+    /// record progress made on a task after it left the queue (e.g. its receipt)
+    pub fn update(&mut self, outcome: Task) -> Result<(), String> {
+        let pos = match self.acked.iter().position(|a| a.task.key == outcome.task_key) {
+            Some(p) => p,
+            None => return Ok(()),  //not a queued task (e.g. the dummy task) or already compacted away
+        };
+        self.append(&JournalRecord::Update { outcome: outcome.clone() })?;
+        self.acked[pos].outcome = outcome;
+        if self.appended >= COMPACT_THRESHOLD {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// queued tasks whose `not_before` has not come yet
+    pub fn delayed(&self) -> usize {
+        let now = Utc::now().timestamp();
+        self.pending.iter().filter(|t| !t.is_due(now)).count()
+    }
+
+    /// seconds until `excess` more tasks have probably left the queue, from the recent ack rate
+    pub fn retry_after(&self, excess: usize) -> u64 {
+        let now = Utc::now().timestamp();
+        let recent: Vec<i64> = self.acked.iter().rev()
+            .map(|a| a.acked_at)
+            .take_while(|at| *at >= now - DRAIN_RATE_WINDOW_SECS)
+            .collect();
+        let oldest = match recent.last() {
+            Some(at) => *at,
+            None => return DEFAULT_RETRY_AFTER_SECS,
+        };
+        // measured over the span the acks cover, a requestor up for a minute has no 5 minute history
+        let per_sec = recent.len() as f64 / (now - oldest).max(MIN_DRAIN_RATE_SPAN_SECS) as f64;
+        ((excess as f64 / per_sec).ceil() as u64).clamp(1, MAX_RETRY_AFTER_SECS)
+    }
+
+    fn depth_changed(&mut self) {
+        let depth = self.pending.len();
+        metrics::QUEUE_DEPTH.set(depth as i64);
+        let mark = config::current().queue.high_water_mark;
+        let above = mark > 0 && depth >= mark;
+        if above != self.above_high_water {
+            if above {
+                warn!("task queue reached its high-water mark with {} tasks queued (mark {})", depth, mark);
+            } else {
+                info!("task queue back below its high-water mark with {} tasks queued", depth);
+            }
+            self.above_high_water = above;
+            metrics::QUEUE_ABOVE_HIGH_WATER.set(above as i64);
+        }
+    }
+
+    /// every task record still held in the journal, used to rebuild the task table
+    pub fn tasks(&self) -> Vec<Task> {
+        let mut all: Vec<Task> = self.acked.iter().map(|a| a.outcome.clone()).collect();
+        all.extend(self.pending.iter().map(|t| {
+            let mut task = Task::new(&t.key);
+            task.params = t.params.clone();
+            task.client = t.client.clone();
+            task.idempotency_key = t.idempotency_key.clone();
+            task.labels = t.labels.clone();
+            task.priority = t.priority;
+            task.deadline = t.deadline;
+            task.not_before = t.not_before;
+            task.network = t.network.clone();
+            task.created_at = t.received_at;
+            task
+        }));
+        all
+    }
+
+    fn append(&mut self, record: &JournalRecord) -> Result<(), String> {
+        let file = match self.journal.as_mut() {
+            Some(f) => f,
+            None => return Ok(()),  //queue has not been opened, keep it in memory only
+        };
+        let mut line = serde_json::to_string(record).map_err(|e| e.to_string())?;
+        line.push('\n');
+        let len = file.metadata().map_err(|e| format!("stat journal failed: {}", e))?.len();
+        let written = file.write_all(line.as_bytes()).map_err(|e| format!("write journal failed: {}", e))
+            .and_then(|_| file.sync_data().map_err(|e| format!("sync journal failed: {}", e)));
+        if let Err(e) = written {
+            // a partial line would run into the next record
+            if let Err(t) = file.set_len(len) {
+                error!("truncate the journal back to {} bytes failed: {}", len, t);
+            }
+            return Err(e)
+        }
+        self.appended += 1;
+        Ok(())
+    }
+
+    /// compact the journal now, e.g. before exiting
+    pub fn checkpoint(&mut self) -> Result<(), String> {
+        self.compact()
+    }
+
+    /// rewrite the journal with only the pending tasks and recently finished ones
+    fn compact(&mut self) -> Result<(), String> {
+        let path = match self.path.clone() {
+            Some(p) => p,
+            None => return Ok(()),
+        };
+        let horizon = Utc::now().timestamp() - ACK_RETENTION_SECS;
+        while self.acked.front().is_some_and(|a| a.acked_at < horizon) {
+            self.acked.pop_front();
+        }
+
+        let mut tmp_path = path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+        {
+            let mut tmp = File::create(&tmp_path).map_err(|e| format!("create {:?} failed: {}", tmp_path, e))?;
+            let mut records: Vec<JournalRecord> = Vec::new();
+            for a in self.acked.iter() {
+                records.push(JournalRecord::Push { task: a.task.clone() });
+                records.push(JournalRecord::Ack { id: a.task.id, outcome: a.outcome.clone(), acked_at: a.acked_at });
+            }
+            for t in self.pending.iter() {
+                records.push(JournalRecord::Push { task: t.clone() });
+                if let Some(outcome) = self.broadcasting.get(&t.id) {
+                    records.push(JournalRecord::Broadcasting { id: t.id, outcome: outcome.clone() });
+                }
+            }
+            for r in records.iter() {
+                let mut line = serde_json::to_string(r).map_err(|e| e.to_string())?;
+                line.push('\n');
+                tmp.write_all(line.as_bytes()).map_err(|e| format!("write {:?} failed: {}", tmp_path, e))?;
+            }
+            tmp.sync_all().map_err(|e| format!("sync {:?} failed: {}", tmp_path, e))?;
+        }
+        fs::rename(&tmp_path, &path).map_err(|e| format!("replace journal {:?} failed: {}", path, e))?;
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .map_err(|e| format!("open journal {:?} failed: {}", path, e))?;
+        self.journal = Some(file);
+        self.appended = 0;
+        Ok(())
+    }
+}
+
+/// open the global queue and restore the task status table from it
+pub async fn open_queue(path: &str) -> Result<usize, String> {
+    let mut queue = TASK_QUEUE.lock().await;
+    queue.open(path)?;
+    for t in queue.tasks() {
+        task::insert_task(t).await;
+    }
+    Ok(queue.len())
+}
+
+/// journal the signed tx of a task before it is sent, so a restart follows it instead of
+/// submitting the task again
+pub async fn record_broadcast(task_key: &str, tx_hash: &str) -> Result<(), String> {
+    let mut outcome = match task::get_task(task_key).await {
+        Some(t) => t,
+        None => return Ok(()),
+    };
+    outcome.state = TaskState::Broadcast;
+    outcome.tx_hash = Some(tx_hash.to_string());
+    outcome.submitting = false;
+    outcome.updated_at = Utc::now().timestamp();
+    TASK_QUEUE.lock().await.broadcasting(outcome)
+}
+
+/// persist the latest record of a task that already left the queue
+pub async fn persist_task(outcome: Task) {
+    let key = outcome.task_key.clone();
+    if let Err(e) = TASK_QUEUE.lock().await.update(outcome) {
+        error!("persist task {} failed: {}", key, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static JOURNALS: AtomicUsize = AtomicUsize::new(0);
+
+    fn journal_path() -> String {
+        let n = JOURNALS.fetch_add(1, Ordering::SeqCst);
+        let path = std::env::temp_dir().join(format!("requestor-queue-test-{}-{}.journal", std::process::id(), n));
+        let _ = fs::remove_file(&path);
+        path.to_str().unwrap().to_string()
+    }
+
+    fn queued(key: &str) -> QueuedTask {
+        QueuedTask {
+            id: 0,
+            key: key.to_string(),
+            input: format!("input of {}", key),
+            params: None,
+            client: None,
+            idempotency_key: None,
+            labels: BTreeMap::new(),
+            priority: Priority::Normal,
+            deadline: None,
+            not_before: None,
+            network: None,
+            received_at: 0,
+        }
+    }
+
+    fn outcome(key: &str, state: TaskState) -> Task {
+        let mut t = Task::new(key);
+        t.state = state;
+        t
+    }
+
+    fn reopen(path: &str) -> Result<TaskQueue, String> {
+        let mut queue = TaskQueue::default();
+        queue.open(path)?;
+        Ok(queue)
+    }
+
+    fn keys(queue: &TaskQueue) -> Vec<String> {
+        queue.pending.iter().map(|t| t.key.clone()).collect()
+    }
+
+    fn append_raw(path: &str, text: &str) {
+        let mut f = OpenOptions::new().append(true).open(path).unwrap();
+        f.write_all(text.as_bytes()).unwrap();
+    }
+
+    #[test]
+    fn replay_restores_pushes_batches_and_acks() {
+        let path = journal_path();
+        let mut queue = reopen(&path).unwrap();
+        let a = queue.push_many(vec![queued("a")]).unwrap().remove(0);
+        let batch = queue.push_many(vec![queued("b"), queued("c")]).unwrap();
+        assert_eq!(batch.iter().map(|t| t.id).collect::<Vec<_>>(), vec![a.id + 1, a.id + 2]);
+        queue.ack(a.id, outcome("a", TaskState::Broadcast)).unwrap();
+
+        let queue = reopen(&path).unwrap();
+        assert_eq!(keys(&queue), vec!["b", "c"]);
+        assert_eq!(queue.next_id, a.id + 3);
+        let tasks = queue.tasks();
+        assert_eq!(tasks.len(), 3);
+        assert_eq!(tasks.iter().find(|t| t.task_key == "a").unwrap().state, TaskState::Broadcast);
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn replay_applies_updates_of_acked_tasks() {
+        let path = journal_path();
+        let mut queue = reopen(&path).unwrap();
+        let a = queue.push_many(vec![queued("a")]).unwrap().remove(0);
+        queue.ack(a.id, outcome("a", TaskState::Broadcast)).unwrap();
+        queue.update(outcome("a", TaskState::Settled)).unwrap();
+        queue.update(outcome("unknown", TaskState::Settled)).unwrap();
+
+        let queue = reopen(&path).unwrap();
+        assert!(keys(&queue).is_empty());
+        let tasks = queue.tasks();
+        assert_eq!(tasks.len(), 1);
+        assert_eq!(tasks[0].state, TaskState::Settled);
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn compaction_keeps_pending_and_recent_acks() {
+        let path = journal_path();
+        let mut queue = reopen(&path).unwrap();
+        let pushed = queue.push_many(vec![queued("a"), queued("b"), queued("c")]).unwrap();
+        queue.ack(pushed[1].id, outcome("b", TaskState::Failed)).unwrap();
+        queue.checkpoint().unwrap();
+
+        let journal = fs::read_to_string(&path).unwrap();
+        assert_eq!(journal.lines().count(), 4);  //push + ack of b, a push each for a and c
+        assert!(!journal.contains("push_batch"));
+        let queue = reopen(&path).unwrap();
+        assert_eq!(keys(&queue), vec!["a", "c"]);
+        assert_eq!(queue.tasks().iter().find(|t| t.task_key == "b").unwrap().state, TaskState::Failed);
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn compaction_drops_acks_past_retention() {
+        let path = journal_path();
+        let mut queue = reopen(&path).unwrap();
+        let a = queue.push_many(vec![queued("a")]).unwrap().remove(0);
+        queue.ack(a.id, outcome("a", TaskState::Settled)).unwrap();
+        queue.acked[0].acked_at -= ACK_RETENTION_SECS + 1;
+        queue.checkpoint().unwrap();
+
+        let queue = reopen(&path).unwrap();
+        assert!(queue.tasks().is_empty());
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn torn_last_record_is_skipped() {
+        let path = journal_path();
+        let mut queue = reopen(&path).unwrap();
+        queue.push_many(vec![queued("a")]).unwrap();
+        drop(queue);
+        append_raw(&path, "{\"op\":\"push\",\"task\":{\"id\":1,\"ke");
+
+        let mut queue = reopen(&path).unwrap();
+        assert_eq!(keys(&queue), vec!["a"]);
+        queue.push_many(vec![queued("b")]).unwrap();
+        assert_eq!(keys(&reopen(&path).unwrap()), vec!["a", "b"]);
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn corrupt_record_before_the_last_fails_open() {
+        let path = journal_path();
+        let mut queue = reopen(&path).unwrap();
+        queue.push_many(vec![queued("a")]).unwrap();
+        drop(queue);
+        append_raw(&path, "not a record\n");
+        let mut queue = TaskQueue {
+            path: Some(PathBuf::from(&path)),
+            journal: Some(OpenOptions::new().append(true).open(&path).unwrap()),
+            ..Default::default()
+        };
+        queue.push_many(vec![queued("b")]).unwrap();
+
+        assert!(reopen(&path).err().unwrap().contains("corrupt journal"));
+        assert!(fs::read_to_string(&path).unwrap().contains("not a record"));
+        let _ = fs::remove_file(&path);
+    }
+
+    fn broadcast(key: &str, tx_hash: &str) -> Task {
+        let mut t = outcome(key, TaskState::Broadcast);
+        t.tx_hash = Some(tx_hash.to_string());
+        t
+    }
+
+    #[test]
+    fn task_being_broadcast_is_followed_not_requeued() {
+        let path = journal_path();
+        let mut queue = reopen(&path).unwrap();
+        queue.push_many(vec![queued("a"), queued("b")]).unwrap();
+        queue.broadcasting(broadcast("a", "01")).unwrap();
+        queue.broadcasting(broadcast("a", "02")).unwrap();  //resent with higher fees
+        queue.broadcasting(broadcast("unqueued", "03")).unwrap();
+        drop(queue);
+
+        let queue = reopen(&path).unwrap();
+        assert_eq!(keys(&queue), vec!["b"]);
+        let a = queue.tasks().into_iter().find(|t| t.task_key == "a").unwrap();
+        assert_eq!((a.state, a.tx_hash.as_deref()), (TaskState::Broadcast, Some("02")));
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn ack_after_broadcasting_wins() {
+        let path = journal_path();
+        let mut queue = reopen(&path).unwrap();
+        let a = queue.push_many(vec![queued("a")]).unwrap().remove(0);
+        queue.broadcasting(broadcast("a", "01")).unwrap();
+        queue.ack(a.id, outcome("a", TaskState::Failed)).unwrap();
+        drop(queue);
+
+        let queue = reopen(&path).unwrap();
+        assert_eq!(queue.tasks()[0].state, TaskState::Failed);
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn compaction_keeps_the_tx_being_broadcast() {
+        let path = journal_path();
+        let mut queue = reopen(&path).unwrap();
+        queue.push_many(vec![queued("a")]).unwrap();
+        queue.broadcasting(broadcast("a", "01")).unwrap();
+        queue.checkpoint().unwrap();
+        drop(queue);
+
+        let queue = reopen(&path).unwrap();
+        assert!(keys(&queue).is_empty());
+        assert_eq!(queue.tasks()[0].tx_hash.as_deref(), Some("01"));
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn ack_of_an_unknown_task_changes_nothing() {
+        let mut queue = TaskQueue::default();
+        queue.push_many(vec![queued("a")]).unwrap();
+        assert!(queue.ack(42, outcome("x", TaskState::Failed)).is_err());
+        assert_eq!(keys(&queue), vec!["a"]);
+        assert!(queue.acked.is_empty());
+    }
+
+    fn prioritized(key: &str, priority: Priority) -> QueuedTask {
+        QueuedTask { priority, ..queued(key) }
+    }
+
+    fn next_key(queue: &TaskQueue) -> Option<String> {
+        queue.next_ready(&HashSet::new()).map(|t| t.key.clone())
+    }
+
+    #[test]
+    fn next_ready_takes_high_before_low() {
+        let mut queue = TaskQueue::default();
+        queue.push_many(vec![prioritized("low", Priority::Low), prioritized("normal", Priority::Normal), prioritized("high", Priority::High)]).unwrap();
+        assert_eq!(next_key(&queue).as_deref(), Some("high"));
+        let high = queue.queued_id("high").unwrap();
+        assert_eq!(queue.next_ready(&HashSet::from([high])).map(|t| t.key.as_str()), Some("normal"));
+    }
+
+    #[test]
+    fn next_ready_takes_the_earliest_deadline_first() {
+        let mut queue = TaskQueue::default();
+        let now = Utc::now().timestamp();
+        queue.push_many(vec![
+            QueuedTask { deadline: None, ..queued("none") },
+            QueuedTask { deadline: Some(now + 600), ..queued("later") },
+            QueuedTask { deadline: Some(now + 60), ..queued("sooner") },
+        ]).unwrap();
+        assert_eq!(next_key(&queue).as_deref(), Some("sooner"));
+    }
+
+    #[test]
+    fn next_ready_promotes_a_low_task_after_aging() {
+        let aging_secs = config::current().queue.aging_secs as i64;
+        let mut queue = TaskQueue::default();
+        queue.push_many(vec![prioritized("normal", Priority::Normal), prioritized("low", Priority::Low)]).unwrap();
+        assert_eq!(next_key(&queue).as_deref(), Some("normal"));
+        let low = queue.pending.iter_mut().find(|t| t.key == "low").unwrap();
+        low.received_at -= 2 * aging_secs;   //waited two aging periods, now ranks as high
+        assert_eq!(next_key(&queue).as_deref(), Some("low"));
+    }
+
+    #[test]
+    fn next_ready_skips_a_delayed_task_until_due() {
+        let mut queue = TaskQueue::default();
+        let now = Utc::now().timestamp();
+        queue.push_many(vec![QueuedTask { not_before: Some(now + 3600), ..prioritized("delayed", Priority::High) }]).unwrap();
+        assert_eq!(next_key(&queue), None);
+        queue.push_many(vec![prioritized("due", Priority::Low)]).unwrap();
+        assert_eq!(next_key(&queue).as_deref(), Some("due"));
+        queue.pending[0].not_before = Some(now - 1);
+        assert_eq!(next_key(&queue).as_deref(), Some("delayed"));
+    }
+}
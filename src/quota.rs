@@ -0,0 +1,253 @@
+// Copyright (C) 2019-2022 Aleo Systems Inc.
+// This file is part of the aoraki-labs library.
+
+// The aoraki-labs library is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// The aoraki-labs library is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with the aoraki-labs library. If not, see <https://www.gnu.org/licenses/>.
+
+//! Per-client task ingestion limits.
+//!
+//! Each client (api key, or without one the source address reported by a trusted
+//! proxy, else one shared `anonymous` client) has a token bucket refilled at
+//! `rate_per_sec` up to `burst` tasks, plus daily task and reward quotas that
+//! reset at 00:00 UTC. Usage is kept in memory only.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+use chrono::{NaiveDate, Utc};
+use jsonrpc_http_server::jsonrpc_core::Error;
+use lazy_static::lazy_static;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::config::CONFIG;
+use crate::error::{rpc_error, ErrorCode};
+
+const EVICT_INTERVAL_SECS: i64 = 60;  //how often clients back at their full allowance are forgotten
+
+static LAST_EVICTION: AtomicI64 = AtomicI64::new(0);
+
+lazy_static! {
+    static ref USAGE: Mutex<HashMap<String, ClientUsage>> = {   //client id -> usage
+        Mutex::new(HashMap::new())
+    };
+}
+
+/// limits of one client, a zero daily quota means unlimited
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct QuotaLimits {
+    pub rate_per_sec: f64,
+    pub burst: u64,
+    pub daily_tasks: u64,
+    pub daily_reward: u64,
+}
+
+impl Default for QuotaLimits {
+    fn default() -> Self {
+        QuotaLimits {
+            rate_per_sec: 10.0,
+            burst: 1000,  //a whole batch of the default rpc.max_batch_size
+            daily_tasks: 0,
+            daily_reward: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct QuotaConfig {
+    /// applied to every client without its own entry
+    pub default: QuotaLimits,
+    /// client id (api key name or source address) -> limits
+    pub clients: HashMap<String, QuotaLimits>,
+    /// account callers without an api key by their `X-Forwarded-For`/`X-Real-IP` address,
+    /// only safe behind a proxy that sets these headers itself
+    pub trust_forwarded_for: bool,
+}
+
+impl QuotaConfig {
+    fn limits(&self, client: &str) -> QuotaLimits {
+        self.clients.get(client).cloned().unwrap_or_else(|| self.default.clone())
+    }
+}
+
+#[derive(Debug)]
+struct ClientUsage {
+    tokens: f64,
+    refilled_at: Instant,
+    day: NaiveDate,
+    tasks_today: u64,
+    reward_today: u64,
+}
+
+impl ClientUsage {
+    fn new(limits: &QuotaLimits) -> Self {
+        ClientUsage {
+            tokens: limits.burst as f64,
+            refilled_at: Instant::now(),
+            day: Utc::now().date_naive(),
+            tasks_today: 0,
+            reward_today: 0,
+        }
+    }
+
+    fn refresh(&mut self, limits: &QuotaLimits) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limits.rate_per_sec).min(limits.burst as f64);
+        self.refilled_at = now;
+
+        let today = Utc::now().date_naive();
+        if today != self.day {
+            self.day = today;
+            self.tasks_today = 0;
+            self.reward_today = 0;
+        }
+    }
+
+    /// a fresh entry would be the same, so forgetting this one loses nothing
+    fn is_idle(&self, limits: &QuotaLimits) -> bool {
+        self.tokens >= limits.burst as f64 && self.tasks_today == 0 && self.reward_today == 0
+    }
+}
+
+/// drop the clients back at their full allowance, at most once per `EVICT_INTERVAL_SECS`
+fn evict_idle(usage: &mut HashMap<String, ClientUsage>, config: &QuotaConfig) {
+    let now = Utc::now().timestamp();
+    let last = LAST_EVICTION.load(Ordering::SeqCst);
+    if now - last < EVICT_INTERVAL_SECS || LAST_EVICTION.compare_exchange(last, now, Ordering::SeqCst, Ordering::SeqCst).is_err() {
+        return
+    }
+    usage.retain(|client, u| {
+        let limits = config.limits(client);
+        u.refresh(&limits);
+        !u.is_idle(&limits)
+    });
+}
+
+fn seconds_until_utc_midnight() -> u64 {
+    let now = Utc::now();
+    let tomorrow = now.date_naive().succ_opt().unwrap().and_hms_opt(0, 0, 0).unwrap();
+    (tomorrow - now.naive_utc()).num_seconds().max(1) as u64
+}
+
+/// take `tasks` tasks worth `reward` in total from the client's allowance, all or nothing
+pub fn charge(client: &str, tasks: u64, reward: u64) -> Result<(), Error> {
+    let config = CONFIG.read().unwrap().quota.clone();
+    let limits = config.limits(client);
+    let mut usage = USAGE.lock().unwrap();
+    evict_idle(&mut usage, &config);
+    let u = usage.entry(client.to_string()).or_insert_with(|| ClientUsage::new(&limits));
+    u.refresh(&limits);
+
+    if limits.daily_tasks > 0 && u.tasks_today + tasks > limits.daily_tasks {
+        return Err(rpc_error(ErrorCode::QuotaExceeded, "daily task quota exceeded", Some(json!({
+            "limit": "daily_tasks",
+            "quota": limits.daily_tasks,
+            "used": u.tasks_today,
+            "retry_after": seconds_until_utc_midnight(),
+        }))));
+    }
+    if limits.daily_reward > 0 && u.reward_today + reward > limits.daily_reward {
+        return Err(rpc_error(ErrorCode::QuotaExceeded, "daily reward quota exceeded", Some(json!({
+            "limit": "daily_reward",
+            "quota": limits.daily_reward,
+            "used": u.reward_today,
+            "retry_after": seconds_until_utc_midnight(),
+        }))));
+    }
+    check_rate(u, &limits, tasks)?;
+
+    u.tokens -= tasks as f64;
+    u.tasks_today += tasks;
+    u.reward_today += reward;
+    Ok(())
+}
+
+/// take `calls` from the client's token bucket only, for calls that cost the relayer
+/// work without queueing a task
+pub fn throttle(client: &str, calls: u64) -> Result<(), Error> {
+    let config = CONFIG.read().unwrap().quota.clone();
+    let limits = config.limits(client);
+    let mut usage = USAGE.lock().unwrap();
+    evict_idle(&mut usage, &config);
+    let u = usage.entry(client.to_string()).or_insert_with(|| ClientUsage::new(&limits));
+    u.refresh(&limits);
+    check_rate(u, &limits, calls)?;
+    u.tokens -= calls as f64;
+    Ok(())
+}
+
+fn check_rate(u: &ClientUsage, limits: &QuotaLimits, tasks: u64) -> Result<(), Error> {
+    if (tasks as f64) <= u.tokens {
+        return Ok(())
+    }
+    if tasks > limits.burst {
+        let reason = format!("{} tasks at once are more than the rate limit burst of {} allows, split the batch", tasks, limits.burst);
+        return Err(rpc_error(ErrorCode::RateLimited, &reason, Some(json!({
+            "tasks": tasks,
+            "burst": limits.burst,
+        }))));
+    }
+    if limits.rate_per_sec <= 0.0 {
+        return Err(rpc_error(ErrorCode::RateLimited, "the rate limit allowance is used up and never refills", Some(json!({
+            "burst": limits.burst,
+        }))));
+    }
+    let retry_after = ((tasks as f64 - u.tokens) / limits.rate_per_sec).ceil() as u64;
+    Err(rpc_error(ErrorCode::RateLimited, "task rate limit exceeded", Some(json!({
+        "rate_per_sec": limits.rate_per_sec,
+        "burst": limits.burst,
+        "retry_after": retry_after.max(1),
+    }))))
+}
+
+/// give back an allowance taken by `charge` for tasks that were not queued after all
+pub fn refund(client: &str, tasks: u64, reward: u64) {
+    let limits = CONFIG.read().unwrap().quota.limits(client);
+    let mut usage = USAGE.lock().unwrap();
+    if let Some(u) = usage.get_mut(client) {
+        u.tokens = (u.tokens + tasks as f64).min(limits.burst as f64);
+        u.tasks_today = u.tasks_today.saturating_sub(tasks);
+        u.reward_today = u.reward_today.saturating_sub(reward);
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct UsageReport {
+    pub client: String,
+    pub limits: QuotaLimits,
+    pub tokens_available: f64,
+    pub day: String,
+    pub tasks_today: u64,
+    pub reward_today: u64,
+}
+
+pub fn usage(client: &str) -> UsageReport {
+    let limits = CONFIG.read().unwrap().quota.limits(client);
+    let mut usage = USAGE.lock().unwrap();
+    let mut fresh = ClientUsage::new(&limits);
+    let u = usage.get_mut(client).unwrap_or(&mut fresh);  //looking a client up does not track it
+    u.refresh(&limits);
+    UsageReport {
+        client: client.to_string(),
+        tokens_available: u.tokens.floor(),
+        day: u.day.to_string(),
+        tasks_today: u.tasks_today,
+        reward_today: u.reward_today,
+        limits,
+    }
+}
@@ -15,78 +15,466 @@
 // along with the aoraki-labs library. If not, see <https://www.gnu.org/licenses/>.
 
 
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::collections::BTreeMap;
+use std::sync::Arc;
 
-use chrono::format::format;
-use jsonrpc_http_server::jsonrpc_core::{IoHandler, Params, Value};
-use jsonrpc_http_server::ServerBuilder;
+use chrono::Utc;
+use jsonrpc_http_server::jsonrpc_core::{Error, MetaIoHandler, Params, Value};
+use jsonrpc_http_server::{hyper, RequestMiddlewareAction, ServerBuilder};
 use log::*;
-use web3::signing::keccak256;
+use serde_derive::Deserialize;
+use serde_json::json;
 
-use crate::chain::{self, TASK_MSG_QUEUE, process_task_data};
+use crate::admin;
+use crate::auth::{self, AuthMiddleware, Caller, Role};
+use crate::chain;
+use crate::config;
+use crate::health;
+use crate::metrics;
+use crate::network::{self, Network};
+use crate::error::{self, invalid_params, rpc_error, with_data, ErrorCode};
+use crate::queue::{QueuedTask, TaskQueue, TASK_QUEUE};
+use crate::quota;
+use crate::shutdown;
+use crate::worker;
+use crate::task::{self, Priority, Task, TaskFilter, TaskState, TaskParams, TaskParamsRequest};
 
+const MAX_IDEMPOTENCY_KEY_LEN: usize = 256;
+const MAX_LABELS: usize = 16;
+const MAX_LABEL_LEN: usize = 128;
+const DEFAULT_PAGE_SIZE: usize = 100;
+const MAX_PAGE_SIZE: usize = 1000;
 
-pub async fn start_rpc_server(addr:String) -> jsonrpc_http_server::Server {
-    let mut io = IoHandler::default();
 
-    io.add_method("ReceiveTask", |params: Params| async {   //receive user side paramter and then cache/submit one proof task
-        info!("receive ReceiveTask msg of {:?}",params.clone());
-        let req_input: Vec<Value> = match params.parse(){
-            Ok(r) => r,
-            Err(_) => {
-                return Ok(Value::String("parameter invalid".to_string()))
+pub fn start_rpc_server(addr:String) -> jsonrpc_http_server::Server {
+    let mut io: MetaIoHandler<Caller, AuthMiddleware> = MetaIoHandler::with_middleware(AuthMiddleware);
+
+    io.add_method_with_meta("ReceiveTask", |params: Params, caller: Caller| async move {   //receive user side paramter and then cache/submit one proof task
+        info!("receive ReceiveTask msg of {:?} from {}",params.clone(),caller.display_name());
+        let req = parse_task_request(params)?;
+        let new_task = prepare_task(req)?;
+
+        let mut keys = enqueue_tasks(&caller, vec![new_task]).await?;
+
+        Ok(Value::String(keys.remove(0)))
+
+    });
+    io.add_method_with_meta("ReceiveTasks", |params: Params, caller: Caller| async move {   //receive a batch of tasks, queued all or nothing
+        let items: Vec<Value> = match params {
+            Params::Array(mut a) if a.len() == 1 => match a.remove(0) {
+                Value::Array(items) => items,
+                _ => return Err(invalid_params("expected [[task, ...]]")),
+            },
+            Params::Map(mut m) => match m.remove("tasks") {
+                Some(Value::Array(items)) => items,
+                _ => return Err(invalid_params("expected {\"tasks\": [task, ...]}")),
             },
+            _ => return Err(invalid_params("expected [[task, ...]]")),
         };
-        if req_input.len() != 1 {
-            return Ok(Value::String("parameter invalid".to_string()))
+        info!("receive ReceiveTasks msg of {} tasks from {}",items.len(),caller.display_name());
+        let max_batch_size = config::current().rpc.max_batch_size;
+        if items.is_empty() || items.len() > max_batch_size {
+            return Err(invalid_params(&format!("batch must hold between 1 and {} tasks", max_batch_size)))
         }
 
-        let random_number = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap().as_millis(); 
+        let mut new_tasks = Vec::with_capacity(items.len());
+        for (i, item) in items.into_iter().enumerate() {
+            let new_task = match parse_task_item(item).and_then(prepare_task) {
+                Ok(t) => t,
+                Err(e) => return Err(with_data(e, "index", Value::from(i))),
+            };
+            new_tasks.push(new_task);
+        }
+        let keys = enqueue_tasks(&caller, new_tasks).await?;
 
-        let task=format!("{}{}{}",req_input[0].to_string(),"#".to_string(),random_number.to_string());
-        let result = hex::encode(keccak256(&task.clone().into_bytes()));
+        Ok(Value::Array(keys.into_iter().map(Value::String).collect()))
+    });
+    io.add_method_with_meta("CancelTask", |params: Params, caller: Caller| async move {   //withdraw a task that was not submitted on chain yet
+        let req_input: Vec<String> = params.parse().map_err(|_| invalid_params("expected [task_key]"))?;
+        if req_input.len() != 1 {
+            return Err(invalid_params("expected exactly one task key"))
+        }
+        let task_key = req_input[0].trim_start_matches("0x").to_string();
+        info!("receive CancelTask msg of {} from {}",task_key,caller.display_name());
 
-        receive_task(task).await;  
+        let current = task::get_task(&task_key).await
+            .ok_or_else(|| rpc_error(ErrorCode::TaskNotFound, "no task with this key", Some(json!({ "task_key": task_key }))))?;
+        if current.client.as_deref() != Some(caller.client_id().as_str()) {
+            auth::authorize(&caller, Role::Admin)?;
+        }
 
-        Ok(Value::String(result))
-        
+        let cancelled = match task::cancel(&task_key, &format!("cancelled by {}", caller.client_id())).await {
+            Ok(t) => t,
+            Err(None) => return Err(rpc_error(ErrorCode::TaskNotFound, "no task with this key", Some(json!({ "task_key": task_key })))),
+            Err(Some(t)) => return Err(not_cancellable(&t)),
+        };
+        let mut queue = TASK_QUEUE.lock().await;
+        if let Some(id) = queue.queued_id(&task_key) {
+            if let Err(e) = queue.ack(id, cancelled.clone()) {
+                error!("record the cancellation of task {} failed: {}", task_key, e);
+                return Err(rpc_error(ErrorCode::QueueUnavailable, &e, None))
+            }
+        }
+        serde_json::to_value(cancelled).map_err(|e| rpc_error(ErrorCode::Internal, &e.to_string(), None))
     });
-    io.add_method("Test", |params: Params| async { //just for test
-        let _: Vec<Value> = match params.parse(){
+    io.add_method_with_meta("GetUsage", |params: Params, caller: Caller| async move {   //rate limit and quota usage, admins may name another client
+        let req_input: Vec<String> = match params {
+            Params::None => Vec::new(),
+            p => p.parse().map_err(|_| invalid_params("expected [] or [client]"))?,
+        };
+        let client = match req_input.first() {
+            None => caller.client_id(),
+            Some(c) if *c == caller.client_id() => c.clone(),
+            Some(c) => {
+                auth::authorize(&caller, Role::Admin)?;
+                c.clone()
+            },
+        };
+        serde_json::to_value(quota::usage(&client)).map_err(|e| rpc_error(ErrorCode::Internal, &e.to_string(), None))
+    });
+    io.add_method("GetTaskStatus", |params: Params| async {   //look up a task by the key ReceiveTask returned
+        let req_input: Vec<String> = match params.parse(){
             Ok(r) => r,
             Err(_) => {
-                return Ok(Value::String("parameter invalid".to_string()))
+                return Err(invalid_params("expected [task_key]"))
             },
         };
-        chain::test();
-        Ok(Value::String("success".to_string()))
+        if req_input.len() != 1 {
+            return Err(invalid_params("expected exactly one task key"))
+        }
+
+        let task_key = req_input[0].trim_start_matches("0x");
+        match task::get_task(task_key).await {
+            Some(t) => serde_json::to_value(t).map_err(|e| rpc_error(ErrorCode::Internal, &e.to_string(), None)),
+            None => Err(rpc_error(ErrorCode::TaskNotFound, "no task with this key", Some(json!({ "task_key": task_key })))),
+        }
+    });
+    io.add_method("ListTasks", |params: Params| async {   //page through tasks, newest first
+        let req: ListTasksRequest = match params {
+            Params::None => ListTasksRequest::default(),
+            Params::Array(a) if a.is_empty() => ListTasksRequest::default(),
+            Params::Array(mut a) if a.len() == 1 => serde_json::from_value(a.remove(0))
+                .map_err(|e| invalid_params(&format!("invalid filter: {}", e)))?,
+            Params::Map(m) => serde_json::from_value(Value::Object(m))
+                .map_err(|e| invalid_params(&format!("invalid filter: {}", e)))?,
+            _ => return Err(invalid_params("expected [filter]")),
+        };
+        let limit = req.limit.unwrap_or(DEFAULT_PAGE_SIZE);
+        if limit == 0 || limit > MAX_PAGE_SIZE {
+            return Err(invalid_params(&format!("limit must be within [1, {}]", MAX_PAGE_SIZE)))
+        }
+        let (tasks, next_cursor) = task::list(&req.filter, req.cursor.as_deref(), limit).await
+            .map_err(|e| invalid_params(&e))?;
+        Ok(json!({ "tasks": tasks, "next_cursor": next_cursor }))
+    });
+    io.add_method_with_meta("SimulateTask", |params: Params, caller: Caller| async move {   //dry run a task: relayer assignment, encoding, gas estimate and eth_call, nothing is broadcast
+        info!("receive SimulateTask msg of {:?} from {}",params.clone(),caller.display_name());
+        let req = parse_task_request(params)?;
+        let new_task = prepare_task(req)?;
+        // the relayer assignment is real, so it is paced like ingestion
+        ingestion_open()?;
+        quota::throttle(&caller.client_id(), 1)?;
+        let assignment = chain::request_assignment(&new_task.network, new_task.task, &new_task.params).await
+            .map_err(|e| rpc_error(ErrorCode::RelayerUnavailable, &e, None))?;
+        let simulation = chain::simulate_submission(&new_task.network, assignment).await
+            .map_err(|e| rpc_error(ErrorCode::ChainUnavailable, &e, None))?;
+        serde_json::to_value(simulation).map_err(|e| rpc_error(ErrorCode::Internal, &e.to_string(), None))
     });
 
-    info!("start the server on :{}",addr.clone());
+    admin::add_admin_methods(&mut io);
+
+    info!("start the server on :{}",addr);
  
-    let server = ServerBuilder::new(io)
+    ServerBuilder::with_meta_extractor(io, auth::http_caller)
+        .request_middleware(http_routes)
         .threads(2)
         .start_http(&addr.parse().unwrap())
-        .unwrap();
-    
-    server
+        .unwrap()
+}
+
+/// plain http endpoints served next to the json-rpc api
+fn http_routes(req: hyper::Request<hyper::Body>) -> RequestMiddlewareAction {
+    match health::route(&req).or_else(|| metrics::route(&req)) {
+        Some(response) => RequestMiddlewareAction::Respond {
+            should_validate_hosts: false,
+            response: Box::pin(async { Ok(response) }),
+        },
+        None => RequestMiddlewareAction::Proceed { should_continue_on_invalid_cors: false, request: req },
+    }
+}
+
+/// ListTasks params, a task filter plus the page to return
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct ListTasksRequest {
+    #[serde(flatten)]
+    filter: TaskFilter,
+    /// `next_cursor` of the previous page
+    cursor: Option<String>,
+    limit: Option<usize>,
+}
+
+fn not_cancellable(t: &Task) -> Error {
+    // a failed submission leaves no tx behind, its state tells why it is final
+    let reason = if t.tx_hash.is_some() || (t.submitting && !t.state.is_terminal()) {
+        "the task was already submitted on chain".to_string()
+    } else {
+        format!("the task already reached the {} state", t.state.as_str())
+    };
+    rpc_error(ErrorCode::TaskNotCancellable, &reason, Some(json!({
+        "task_key": t.task_key,
+        "state": t.state,
+        "tx_hash": t.tx_hash,
+    })))
 }
 
-pub async fn receive_task(task:String){
-    info!("receive one new task data is {:?}",task);
-    let mut queue = TASK_MSG_QUEUE.lock().await;
-    queue.push_back(task);
+/// ReceiveTask input, either `[input]` or `[{"input": ..., <task params>}]`;
+/// an object without an `input` member is itself the task input
+#[derive(Debug, Deserialize)]
+pub struct TaskRequest {
+    pub input: Value,
+    /// resubmitting with the same key returns the original task instead of queueing another
+    #[serde(default)]
+    pub idempotency_key: Option<String>,
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    #[serde(default)]
+    pub priority: Priority,
+    /// unix seconds, earlier deadlines go first within a priority
+    #[serde(default)]
+    pub deadline: Option<i64>,
+    /// unix seconds, the task is held in the queue until then
+    #[serde(default)]
+    pub not_before: Option<i64>,
+    /// a configured network, the default one when unset
+    #[serde(default)]
+    pub network: Option<String>,
+    #[serde(flatten)]
+    pub params: TaskParamsRequest,
 }
 
-pub async fn loop_task_data() -> web3::Result<()> {
-    let mut queue = TASK_MSG_QUEUE.lock().await;
-    while queue.len() > 0 {
-        info!(" start to process the task data of len : {}",queue.len());
-        let item = queue.pop_front().unwrap();
-        process_task_data(item).await;
+fn parse_task_request(params: Params) -> Result<TaskRequest, Error> {
+    let value = match params {
+        Params::Array(mut items) => {
+            if items.len() != 1 {
+                return Err(invalid_params("expected exactly one task input"))
+            }
+            items.remove(0)
+        },
+        Params::Map(map) => Value::Object(map),
+        Params::None => return Err(invalid_params("expected [input]")),
+    };
+    parse_task_item(value)
+}
+
+fn parse_task_item(value: Value) -> Result<TaskRequest, Error> {
+    match value {
+        Value::Object(map) if map.contains_key("input") => serde_json::from_value(Value::Object(map))
+            .map_err(|e| invalid_params(&format!("invalid task request: {}", e))),
+        input => Ok(TaskRequest {
+            input,
+            idempotency_key: None,
+            labels: BTreeMap::new(),
+            priority: Priority::default(),
+            deadline: None,
+            not_before: None,
+            network: None,
+            params: TaskParamsRequest::default(),
+        }),
+    }
+}
+
+/// a validated task, keyed and ready to be queued
+pub struct NewTask {
+    pub key: String,
+    pub task: String,
+    pub params: TaskParams,
+    pub idempotency_key: Option<String>,
+    pub labels: BTreeMap<String, String>,
+    pub priority: Priority,
+    pub deadline: Option<i64>,
+    pub not_before: Option<i64>,
+    pub network: Arc<Network>,
+}
+
+fn prepare_task(req: TaskRequest) -> Result<NewTask, Error> {
+    let net = network::get(req.network.as_deref()).map_err(|e| invalid_params(&e))?;
+    let params = match net.task_config(&config::current().task).resolve(&req.params) {
+        Ok(p) => p,
+        Err(e) => return Err(invalid_params(&e)),
+    };
+    if let Some(k) = &req.idempotency_key {
+        if k.is_empty() || k.len() > MAX_IDEMPOTENCY_KEY_LEN {
+            return Err(invalid_params(&format!("idempotency_key must hold 1 to {} bytes", MAX_IDEMPOTENCY_KEY_LEN)))
+        }
+    }
+    if req.labels.len() > MAX_LABELS {
+        return Err(invalid_params(&format!("at most {} labels are allowed", MAX_LABELS)))
+    }
+    for (k, v) in req.labels.iter() {
+        if k.is_empty() || k.len() > MAX_LABEL_LEN || v.len() > MAX_LABEL_LEN {
+            return Err(invalid_params(&format!("label names must hold 1 to {} bytes and values at most {}", MAX_LABEL_LEN, MAX_LABEL_LEN)))
+        }
+    }
+    let now = Utc::now().timestamp();
+    if req.deadline.is_some_and(|d| d <= now) {
+        return Err(invalid_params("deadline must be in the future"))
+    }
+    if let Some(at) = req.not_before {
+        let max_delay = config::current().queue.max_delay_secs;
+        if at > now + max_delay as i64 {
+            return Err(invalid_params(&format!("not_before must be within {}s from now", max_delay)))
+        }
+        if req.deadline.is_some_and(|d| d <= at) {
+            return Err(invalid_params("deadline must be after not_before"))
+        }
+    }
+    let task = format!("{}#{}", req.input, task::next_task_stamp());
+    Ok(NewTask {
+        key: task::task_key(&task),
+        task,
+        params,
+        idempotency_key: req.idempotency_key,
+        labels: req.labels,
+        priority: req.priority,
+        deadline: req.deadline,
+        not_before: req.not_before,
+        network: net,
+    })
+}
+
+/// charge the caller's quota, then record and durably queue the tasks as one unit;
+/// returns the key of every task, the original one for a repeated idempotency key
+async fn enqueue_tasks(caller: &Caller, new_tasks: Vec<NewTask>) -> Result<Vec<String>, Error> {
+    ingestion_open()?;
+    let client = caller.client_id();
+    let window = config::current().rpc.idempotency_window_secs as i64;
+    // a repeated key waits for the outcome of the call that claimed it first
+    let _claims = if new_tasks.iter().any(|t| t.idempotency_key.is_some()) {
+        Some(task::IDEMPOTENT_INGESTION.lock().await)
+    } else {
+        None
+    };
+    task::prune_idempotency_keys(window).await;
+
+    let mut keys = Vec::with_capacity(new_tasks.len());
+    let mut fresh = Vec::with_capacity(new_tasks.len());
+    for t in new_tasks {
+        if let Some(k) = &t.idempotency_key {
+            if let Some(existing) = task::claim_idempotency_key(&client, k, &t.key, window).await {
+                info!("idempotency key {} of {} already used for task {}", k, client, existing);
+                keys.push(existing);
+                continue
+            }
+        }
+        keys.push(t.key.clone());
+        fresh.push(t);
+    }
+    if fresh.is_empty() {
+        return Ok(keys)
+    }
+
+    // checked again when queueing, this only spares the quota of calls bound to fail
+    if let Err(e) = check_capacity(&*TASK_QUEUE.lock().await, fresh.len()) {
+        release_idempotency_keys(&client, &fresh).await;
+        return Err(e)
+    }
+
+    let count = fresh.len() as u64;
+    let reward: u64 = fresh.iter().map(|t| t.params.reward).sum();
+    if let Err(e) = quota::charge(&client, count, reward) {
+        release_idempotency_keys(&client, &fresh).await;
+        return Err(e)
+    }
+
+    for t in fresh.iter() {
+        let mut record = Task::new(&t.key).with_params(t.params.clone());
+        record.client = Some(client.clone());
+        record.idempotency_key = t.idempotency_key.clone();
+        record.labels = t.labels.clone();
+        record.priority = t.priority;
+        record.deadline = t.deadline;
+        record.not_before = t.not_before;
+        record.network = Some(t.network.name.clone());
+        task::insert_task(record).await;
+        metrics::TASKS.with_label_values(&[TaskState::Received.as_str()]).inc();
+    }
+    if let Err(e) = receive_tasks(&client, &fresh).await {
+        let reason = error::reason(&e);
+        error!("queue {} tasks failed: {}", fresh.len(), reason);
+        for t in fresh.iter() {
+            task::fail(&t.key, &reason).await;
+        }
+        release_idempotency_keys(&client, &fresh).await;
+        quota::refund(&client, count, reward);
+        return Err(e)
+    }
+    Ok(keys)
+}
+
+fn ingestion_open() -> Result<(), Error> {
+    if admin::ingestion_paused() {
+        return Err(rpc_error(ErrorCode::IngestionPaused, "task ingestion is paused by an operator", None))
+    }
+    if shutdown::requested() {
+        return Err(rpc_error(ErrorCode::IngestionPaused, "the requestor is shutting down", None))
     }
     Ok(())
 }
 
+async fn release_idempotency_keys(client: &str, new_tasks: &[NewTask]) {
+    for t in new_tasks.iter() {
+        if let Some(k) = &t.idempotency_key {
+            task::release_idempotency_key(client, k, &t.key).await;
+        }
+    }
+}
+
+/// reject `tasks` more tasks when they would grow the queue beyond `queue.max_depth`
+fn check_capacity(queue: &TaskQueue, tasks: usize) -> Result<(), Error> {
+    let max_depth = config::current().queue.max_depth;
+    if max_depth == 0 || queue.len() + tasks <= max_depth {
+        return Ok(())
+    }
+    metrics::QUEUE_REJECTIONS.inc();
+    if tasks > max_depth {
+        return Err(rpc_error(ErrorCode::QueueFull, &format!("{} tasks never fit in the queue", tasks), Some(json!({
+            "max_depth": max_depth,
+        }))))
+    }
+    let excess = queue.len() + tasks - max_depth;
+    Err(rpc_error(ErrorCode::QueueFull, "the task queue is full", Some(json!({
+        "depth": queue.len(),
+        "max_depth": max_depth,
+        "retry_after": queue.retry_after(excess),
+    }))))
+}
+
+pub async fn receive_tasks(client: &str, new_tasks: &[NewTask]) -> Result<(), Error>{
+    for t in new_tasks.iter() {
+        info!("receive one new task data is {:?}",t.task);
+    }
+    let items: Vec<QueuedTask> = new_tasks.iter()
+        .map(|t| QueuedTask {
+            id: 0,
+            key: t.key.clone(),
+            input: t.task.clone(),
+            params: Some(t.params.clone()),
+            client: Some(client.to_string()),
+            idempotency_key: t.idempotency_key.clone(),
+            labels: t.labels.clone(),
+            priority: t.priority,
+            deadline: t.deadline,
+            not_before: t.not_before,
+            network: Some(t.network.name.clone()),
+            received_at: 0,
+        })
+        .collect();
+    {
+        let mut queue = TASK_QUEUE.lock().await;
+        check_capacity(&queue, items.len())?;
+        queue.push_many(items).map_err(|e| rpc_error(ErrorCode::QueueUnavailable, &e, None))?;
+    }
+    worker::wake();
+    Ok(())
+}
@@ -0,0 +1,98 @@
+// Copyright (C) 2019-2022 Aleo Systems Inc.
+// This file is part of the aoraki-labs library.
+
+// The aoraki-labs library is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// The aoraki-labs library is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with the aoraki-labs library. If not, see <https://www.gnu.org/licenses/>.
+
+//! Graceful shutdown on SIGINT/SIGTERM.
+//!
+//! Ingestion is refused and no new task is taken off the queue, tasks being
+//! assigned or submitted get until the deadline to finish, then the journal is
+//! checkpointed with whatever is still queued.
+
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use log::*;
+use tokio::time::{self, Duration, Instant};
+
+use crate::queue::TASK_QUEUE;
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+static IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+
+/// exit status when in-flight work had to be abandoned at the deadline
+pub const EXIT_DEADLINE_EXCEEDED: i32 = 2;
+
+pub fn requested() -> bool {
+    REQUESTED.load(Ordering::SeqCst)
+}
+
+/// held while a task is being assigned or submitted, shutdown waits for it
+pub struct WorkGuard;
+
+impl Drop for WorkGuard {
+    fn drop(&mut self) {
+        IN_FLIGHT.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+pub fn begin_work() -> WorkGuard {
+    IN_FLIGHT.fetch_add(1, Ordering::SeqCst);
+    WorkGuard
+}
+
+/// resolves on the first SIGINT or SIGTERM
+pub async fn signal() {
+    let mut term = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("can not listen for SIGTERM: {}", e);
+            let _ = tokio::signal::ctrl_c().await;
+            return
+        },
+    };
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => info!("received SIGINT"),
+        _ = term.recv() => info!("received SIGTERM"),
+    }
+}
+
+/// stop taking work, wait up to `deadline` for in-flight tasks and checkpoint the queue;
+/// returns the process exit status
+pub async fn graceful(deadline: Duration) -> i32 {
+    REQUESTED.store(true, Ordering::SeqCst);
+    warn!("shutting down, waiting up to {}s for in-flight tasks", deadline.as_secs());
+
+    let until = Instant::now() + deadline;
+    let mut status = 0;
+    loop {
+        let busy = IN_FLIGHT.load(Ordering::SeqCst);
+        if busy == 0 {
+            break
+        }
+        if Instant::now() >= until {
+            error!("{} tasks still being assigned or submitted at the shutdown deadline, the ones with a signed tx are followed as broadcast on restart, the others retried", busy);
+            status = EXIT_DEADLINE_EXCEEDED;
+            break
+        }
+        time::sleep(Duration::from_millis(100)).await;
+    }
+
+    let mut queue = TASK_QUEUE.lock().await;
+    if let Err(e) = queue.checkpoint() {
+        error!("checkpoint the task queue failed: {}", e);
+        status = 1;
+    }
+    info!("shutdown complete with {} tasks left in the queue, exit status {}", queue.len(), status);
+    status
+}
@@ -0,0 +1,513 @@
+// Copyright (C) 2019-2022 Aleo Systems Inc.
+// This file is part of the aoraki-labs library.
+
+// The aoraki-labs library is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// The aoraki-labs library is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with the aoraki-labs library. If not, see <https://www.gnu.org/licenses/>.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use chrono::Utc;
+use lazy_static::lazy_static;
+use log::*;
+use serde_derive::{Deserialize, Serialize};
+use web3::signing::keccak256;
+
+use crate::metrics;
+
+const TASK_EVENT_CAPACITY: usize = 1024;
+
+lazy_static! {
+    pub static ref TASKS: tokio::sync::Mutex<HashMap<String, Task>> = {   //task key -> task record
+        tokio::sync::Mutex::new(HashMap::new())
+    };
+    pub static ref TASK_EVENTS: tokio::sync::broadcast::Sender<Task> = {   //every new or changed task record
+        tokio::sync::broadcast::channel(TASK_EVENT_CAPACITY).0
+    };
+    static ref IDEMPOTENCY_KEYS: tokio::sync::Mutex<HashMap<(String, String), (String, i64)>> = {   //(client, idempotency key) -> (task key, created at)
+        tokio::sync::Mutex::new(HashMap::new())
+    };
+    pub static ref IDEMPOTENT_INGESTION: tokio::sync::Mutex<()> = {   //held from claiming idempotency keys until their tasks are queued or the claims released
+        tokio::sync::Mutex::new(())
+    };
+}
+
+/// lifecycle of a task:
+///
+/// Received -> Assigning -> Assigned -> Broadcast -> Mined -> Settled
+///
+/// any state before Settled may move to Failed, a task whose relayer
+/// expiry passes before it is mined moves to Expired, and a task not yet
+/// handed to the chain may be Cancelled
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TaskState {
+    Received,
+    Assigning,
+    Assigned,
+    Broadcast,
+    Mined,
+    Failed,
+    Expired,
+    Settled,
+    Cancelled,
+}
+
+impl TaskState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TaskState::Received => "received",
+            TaskState::Assigning => "assigning",
+            TaskState::Assigned => "assigned",
+            TaskState::Broadcast => "broadcast",
+            TaskState::Mined => "mined",
+            TaskState::Failed => "failed",
+            TaskState::Expired => "expired",
+            TaskState::Settled => "settled",
+            TaskState::Cancelled => "cancelled",
+        }
+    }
+
+    pub fn is_terminal(&self) -> bool {
+        matches!(self, TaskState::Failed | TaskState::Expired | TaskState::Settled | TaskState::Cancelled)
+    }
+
+    pub fn can_transition_to(&self, next: TaskState) -> bool {
+        use TaskState::*;
+        matches!(
+            (self, next),
+            (Received, Assigning)
+                | (Assigning, Assigned)
+                | (Assigned, Broadcast)
+                | (Broadcast, Mined)
+                | (Mined, Broadcast)    //the mining block was reorged out
+                | (Mined, Settled)
+                | (Received | Assigning | Assigned | Broadcast | Mined, Failed)
+                | (Assigning | Assigned | Broadcast, Expired)
+                | (Received | Assigning | Assigned, Cancelled)
+        )
+    }
+}
+
+/// scheduling class of a task, higher classes are taken off the queue first
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Priority {
+    High,
+    #[default]
+    Normal,
+    Low,
+}
+
+impl Priority {
+    /// 0 for the highest class
+    pub fn rank(&self) -> u64 {
+        match self {
+            Priority::High => 0,
+            Priority::Normal => 1,
+            Priority::Low => 2,
+        }
+    }
+}
+
+/// economic terms of a task, forwarded to the relayer
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TaskParams {
+    pub reward_token: String,
+    pub reward: u64,
+    pub liability_token: String,
+    pub liability: u64,
+    pub liability_window: u64,
+    /// seconds from assignment within which the relayer expiry must fall
+    pub max_expiry: u64,
+}
+
+/// the terms a client may override in ReceiveTask, unset fields take the configured defaults
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TaskParamsRequest {
+    pub reward_token: Option<String>,
+    pub reward: Option<u64>,
+    pub liability_token: Option<String>,
+    pub liability: Option<u64>,
+    pub liability_window: Option<u64>,
+    pub max_expiry: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Task {
+    pub task_key: String,
+    pub state: TaskState,
+    #[serde(default)]
+    pub params: Option<TaskParams>,
+    /// who submitted the task, see `Caller::client_id`
+    #[serde(default)]
+    pub client: Option<String>,
+    #[serde(default)]
+    pub idempotency_key: Option<String>,
+    /// free-form labels set by the client
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    #[serde(default)]
+    pub priority: Priority,
+    /// unix seconds, orders tasks of the same priority earliest first
+    #[serde(default)]
+    pub deadline: Option<i64>,
+    /// unix seconds before which the task is held in the queue
+    #[serde(default)]
+    pub not_before: Option<i64>,
+    /// network the task is submitted to, the default one when unset
+    #[serde(default)]
+    pub network: Option<String>,
+    pub prover: Option<String>,
+    pub expiry: Option<u64>,
+    pub tx_hash: Option<String>,
+    #[serde(default)]
+    pub block_number: Option<u64>,
+    #[serde(default)]
+    pub gas_used: Option<u64>,
+    #[serde(default)]
+    pub receipt_status: Option<u64>,
+    #[serde(default)]
+    pub confirmations: Option<u64>,
+    pub error: Option<String>,
+    pub created_at: i64,
+    pub updated_at: i64,
+    /// set once the submitTask transaction is being sent, the task can not be cancelled anymore
+    #[serde(skip)]
+    pub submitting: bool,
+}
+
+impl Task {
+    pub fn new(key: &str) -> Self {
+        let now = Utc::now().timestamp();
+        Task {
+            task_key: key.to_string(),
+            state: TaskState::Received,
+            params: None,
+            client: None,
+            idempotency_key: None,
+            labels: BTreeMap::new(),
+            priority: Priority::Normal,
+            deadline: None,
+            not_before: None,
+            network: None,
+            prover: None,
+            expiry: None,
+            tx_hash: None,
+            block_number: None,
+            gas_used: None,
+            receipt_status: None,
+            confirmations: None,
+            error: None,
+            created_at: now,
+            updated_at: now,
+            submitting: false,
+        }
+    }
+
+    pub fn with_params(mut self, params: TaskParams) -> Self {
+        self.params = Some(params);
+        self
+    }
+
+    /// move to `next`, rejecting transitions the lifecycle does not allow
+    pub fn transition(&mut self, next: TaskState) -> Result<(), String> {
+        if !self.state.can_transition_to(next) {
+            return Err(format!("invalid task state transition {:?} -> {:?}", self.state, next));
+        }
+        self.state = next;
+        self.updated_at = Utc::now().timestamp();
+        Ok(())
+    }
+}
+
+static LAST_TASK_STAMP: AtomicU64 = AtomicU64::new(0);
+
+/// current unix millis, bumped when needed so no two tasks share a stamp (and so a key)
+pub fn next_task_stamp() -> u64 {
+    let now = Utc::now().timestamp_millis() as u64;
+    let mut last = LAST_TASK_STAMP.load(Ordering::SeqCst);
+    loop {
+        let stamp = now.max(last + 1);
+        match LAST_TASK_STAMP.compare_exchange(last, stamp, Ordering::SeqCst, Ordering::SeqCst) {
+            Ok(_) => return stamp,
+            Err(actual) => last = actual,
+        }
+    }
+}
+
+/// which tasks a listing or subscription is about, unset fields match everything
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct TaskFilter {
+    pub states: Option<Vec<TaskState>>,
+    /// created at or after, unix seconds
+    pub from: Option<i64>,
+    /// created before, unix seconds
+    pub to: Option<i64>,
+    pub prover: Option<String>,
+    pub client: Option<String>,
+    pub network: Option<String>,
+    /// every label given must be set to the same value
+    pub labels: BTreeMap<String, String>,
+}
+
+impl TaskFilter {
+    pub fn matches(&self, t: &Task) -> bool {
+        self.states.as_ref().is_none_or(|s| s.contains(&t.state))
+            && self.from.is_none_or(|from| t.created_at >= from)
+            && self.to.is_none_or(|to| t.created_at < to)
+            && self.prover.as_ref().is_none_or(|p| t.prover.as_ref().is_some_and(|tp| tp.eq_ignore_ascii_case(p)))
+            && self.client.as_ref().is_none_or(|c| t.client.as_ref() == Some(c))
+            && self.network.as_ref().is_none_or(|n| t.network.as_ref() == Some(n))
+            && self.labels.iter().all(|(k, v)| t.labels.get(k) == Some(v))
+    }
+}
+
+/// newest tasks first matching `filter`, starting after the `cursor` a previous page returned;
+/// also returns the cursor of the next page if there is one
+pub async fn list(filter: &TaskFilter, cursor: Option<&str>, limit: usize) -> Result<(Vec<Task>, Option<String>), String> {
+    let after = match cursor {
+        Some(c) => Some(parse_cursor(c)?),
+        None => None,
+    };
+    let tasks = TASKS.lock().await;
+    let mut matching: Vec<&Task> = tasks.values()
+        .filter(|t| filter.matches(t))
+        .filter(|t| after.as_ref().is_none_or(|(at, key)| (t.created_at, &t.task_key) < (*at, key)))
+        .collect();
+    matching.sort_by(|a, b| (b.created_at, &b.task_key).cmp(&(a.created_at, &a.task_key)));
+
+    let next = if matching.len() > limit {
+        matching.get(limit - 1).map(|t| format!("{}:{}", t.created_at, t.task_key))
+    } else {
+        None
+    };
+    Ok((matching.into_iter().take(limit).cloned().collect(), next))
+}
+
+fn parse_cursor(cursor: &str) -> Result<(i64, String), String> {
+    let (at, key) = cursor.split_once(':').ok_or("invalid cursor")?;
+    let at = at.parse().map_err(|_| "invalid cursor")?;
+    Ok((at, key.to_string()))
+}
+
+/// the key handed back to the client, hex(keccak256(input#millis))
+pub fn task_key(task: &str) -> String {
+    hex::encode(keccak256(task.as_bytes()))
+}
+
+fn publish(task: &Task) {
+    // no receivers just means nobody is subscribed
+    let _ = TASK_EVENTS.send(task.clone());
+}
+
+/// reserve an idempotency key of `client` for `task_key`; if the key was used within
+/// the last `window` seconds the task it was first used for is returned instead.
+/// Hold `IDEMPOTENT_INGESTION` until the task is queued or the claim released, so no
+/// other call is handed the key of a task that may never exist
+pub async fn claim_idempotency_key(client: &str, key: &str, task_key: &str, window: i64) -> Option<String> {
+    let now = Utc::now().timestamp();
+    let mut keys = IDEMPOTENCY_KEYS.lock().await;
+    let id = (client.to_string(), key.to_string());
+    if let Some((existing, created_at)) = keys.get(&id) {
+        if *created_at > now - window {
+            return Some(existing.clone())
+        }
+    }
+    keys.insert(id, (task_key.to_string(), now));
+    None
+}
+
+/// give up a claim whose task never got queued
+pub async fn release_idempotency_key(client: &str, key: &str, task_key: &str) {
+    let mut keys = IDEMPOTENCY_KEYS.lock().await;
+    let id = (client.to_string(), key.to_string());
+    if keys.get(&id).is_some_and(|(k, _)| k == task_key) {
+        keys.remove(&id);
+    }
+}
+
+/// forget idempotency keys older than `window` seconds
+pub async fn prune_idempotency_keys(window: i64) {
+    let horizon = Utc::now().timestamp() - window;
+    IDEMPOTENCY_KEYS.lock().await.retain(|_, (_, created_at)| *created_at > horizon);
+}
+
+pub async fn insert_task(task: Task) {
+    if let (Some(client), Some(key)) = (&task.client, &task.idempotency_key) {
+        IDEMPOTENCY_KEYS.lock().await
+            .entry((client.clone(), key.clone()))
+            .or_insert_with(|| (task.task_key.clone(), task.created_at));
+    }
+    let mut tasks = TASKS.lock().await;
+    publish(&task);
+    tasks.insert(task.task_key.clone(), task);
+}
+
+/// drop finished tasks last changed more than `retention` seconds ago, as the journal
+/// does, so the table does not grow for ever
+pub async fn evict_finished(retention: i64) -> usize {
+    let horizon = Utc::now().timestamp() - retention;
+    let mut tasks = TASKS.lock().await;
+    let before = tasks.len();
+    tasks.retain(|_, t| !(t.state.is_terminal() && t.updated_at < horizon));
+    before - tasks.len()
+}
+
+pub async fn get_task(key: &str) -> Option<Task> {
+    TASKS.lock().await.get(key).cloned()
+}
+
+/// apply `f` and move the task to `next`; the record is left untouched if the transition is invalid
+pub async fn transition<F: FnOnce(&mut Task)>(key: &str, next: TaskState, f: F) -> Result<Task, String> {
+    let mut tasks = TASKS.lock().await;
+    let task = match tasks.get_mut(key) {
+        Some(t) => t,
+        None => return Err(format!("unknown task {}", key)),
+    };
+    let mut updated = task.clone();
+    updated.transition(next)?;
+    f(&mut updated);
+    *task = updated.clone();
+    debug!("task {} is now {:?}", key, next);
+    metrics::TASKS.with_label_values(&[next.as_str()]).inc();
+    publish(&updated);
+    Ok(updated)
+}
+
+/// apply `f` without changing the task state
+pub async fn update<F: FnOnce(&mut Task)>(key: &str, f: F) -> Result<Task, String> {
+    let mut tasks = TASKS.lock().await;
+    let task = match tasks.get_mut(key) {
+        Some(t) => t,
+        None => return Err(format!("unknown task {}", key)),
+    };
+    f(task);
+    task.updated_at = Utc::now().timestamp();
+    publish(task);
+    Ok(task.clone())
+}
+
+/// cancel a task the chain has not seen yet; otherwise the current record is returned
+/// as the error, `None` for an unknown task
+pub async fn cancel(key: &str, reason: &str) -> Result<Task, Option<Task>> {
+    let mut tasks = TASKS.lock().await;
+    let task = tasks.get_mut(key).ok_or(None)?;
+    if task.submitting {
+        return Err(Some(task.clone()))
+    }
+    let mut updated = task.clone();
+    if updated.transition(TaskState::Cancelled).is_err() {
+        return Err(Some(task.clone()))
+    }
+    updated.error = Some(reason.to_string());
+    *task = updated.clone();
+    metrics::TASKS.with_label_values(&[TaskState::Cancelled.as_str()]).inc();
+    info!("task {} cancelled: {}", key, reason);
+    publish(&updated);
+    Ok(updated)
+}
+
+pub async fn is_cancelled(key: &str) -> bool {
+    get_task(key).await.is_some_and(|t| t.state == TaskState::Cancelled)
+}
+
+/// claim an assigned task for submission, after which it can no longer be cancelled;
+/// false if it was cancelled in the meantime
+pub async fn begin_submit(key: &str) -> Result<bool, String> {
+    let mut tasks = TASKS.lock().await;
+    match tasks.get_mut(key) {
+        Some(t) if t.state == TaskState::Assigned => {
+            t.submitting = true;
+            Ok(true)
+        },
+        Some(t) if t.state == TaskState::Cancelled => Ok(false),
+        Some(t) => Err(format!("task {} is {:?}, it can not be submitted", key, t.state)),
+        None => Err(format!("unknown task {}", key)),
+    }
+}
+
+/// mark a task failed with the reason, unless it already reached a terminal state
+pub async fn fail(key: &str, reason: &str) {
+    match get_task(key).await {
+        Some(t) if t.state.is_terminal() => return,
+        _ => (),
+    }
+    if let Err(e) = transition(key, TaskState::Failed, |t| t.error = Some(reason.to_string())).await {
+        warn!("can not fail task {}: {}", key, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use TaskState::*;
+
+    const ALL: [TaskState; 9] = [Received, Assigning, Assigned, Broadcast, Mined, Failed, Expired, Settled, Cancelled];
+
+    #[test]
+    fn happy_path_transitions_are_allowed() {
+        let path = [Received, Assigning, Assigned, Broadcast, Mined, Settled];
+        for pair in path.windows(2) {
+            assert!(pair[0].can_transition_to(pair[1]), "{:?} -> {:?}", pair[0], pair[1]);
+        }
+        assert!(Mined.can_transition_to(Broadcast));
+    }
+
+    #[test]
+    fn terminal_states_never_move() {
+        for from in [Failed, Expired, Settled, Cancelled] {
+            for to in ALL {
+                assert!(!from.can_transition_to(to), "{:?} -> {:?}", from, to);
+            }
+        }
+    }
+
+    #[test]
+    fn failure_expiry_and_cancellation_only_from_their_states() {
+        for from in ALL {
+            assert_eq!(from.can_transition_to(Failed), matches!(from, Received | Assigning | Assigned | Broadcast | Mined));
+            assert_eq!(from.can_transition_to(Expired), matches!(from, Assigning | Assigned | Broadcast));
+            assert_eq!(from.can_transition_to(Cancelled), matches!(from, Received | Assigning | Assigned));
+        }
+    }
+
+    #[tokio::test]
+    async fn only_old_finished_tasks_are_evicted() {
+        let old = Utc::now().timestamp() - 100;
+        for (key, state) in [("evict-settled", Settled), ("evict-failed", Failed), ("evict-broadcast", Broadcast)] {
+            let mut t = Task::new(key);
+            t.state = state;
+            t.updated_at = old;
+            insert_task(t).await;
+        }
+        insert_task(Task { state: Settled, ..Task::new("evict-recent") }).await;
+
+        evict_finished(50).await;
+        assert!(get_task("evict-settled").await.is_none());
+        assert!(get_task("evict-failed").await.is_none());
+        assert!(get_task("evict-broadcast").await.is_some());
+        assert!(get_task("evict-recent").await.is_some());
+    }
+
+    #[test]
+    fn states_are_not_skipped_or_repeated() {
+        assert!(!Received.can_transition_to(Broadcast));
+        assert!(!Assigning.can_transition_to(Mined));
+        assert!(!Broadcast.can_transition_to(Settled));
+        for s in ALL {
+            assert!(!s.can_transition_to(s), "{:?} -> {:?}", s, s);
+        }
+    }
+}
@@ -0,0 +1,180 @@
+// Copyright (C) 2019-2022 Aleo Systems Inc.
+// This file is part of the aoraki-labs library.
+
+// The aoraki-labs library is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// The aoraki-labs library is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with the aoraki-labs library. If not, see <https://www.gnu.org/licenses/>.
+
+//! Worker pool processing the task queue.
+//!
+//! A dispatcher hands queued tasks over a channel to `worker.concurrency`
+//! workers whenever one is idle, so several tasks wait on the relayer at once
+//! and ingestion only ever holds the queue lock for a push. A task stays in the
+//! queue until its worker records the outcome. The chain submissions of each
+//! network are serialized in `chain::submit_task` to keep the nonces in order.
+
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+
+use lazy_static::lazy_static;
+use log::*;
+use serde_derive::{Deserialize, Serialize};
+use tokio::sync::{mpsc, Notify, OwnedSemaphorePermit, Semaphore};
+use tokio::time::{self, Duration};
+
+use crate::admin;
+use crate::chain::process_task_data;
+use crate::config;
+use crate::queue::{QueuedTask, TASK_QUEUE};
+use crate::shutdown::{self, WorkGuard};
+use crate::task::{self, Task};
+
+const IDLE_POLL_SECS: u64 = 1;
+
+lazy_static! {
+    static ref QUEUED: Notify = Notify::new();   //wakes the dispatcher when tasks are pushed
+    static ref DISPATCHED: Mutex<HashSet<u64>> = {   //queue ids handed to a worker and not yet acked
+        Mutex::new(HashSet::new())
+    };
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WorkerConfig {
+    /// tasks processed at once
+    pub concurrency: usize,
+}
+
+impl Default for WorkerConfig {
+    fn default() -> Self {
+        WorkerConfig {
+            concurrency: 4,
+        }
+    }
+}
+
+struct Job {
+    task: QueuedTask,
+    _idle: OwnedSemaphorePermit,  //returned to the dispatcher once the job is done
+    _work: WorkGuard,
+}
+
+/// tell the dispatcher new tasks are queued
+pub fn wake() {
+    QUEUED.notify_one();
+}
+
+/// number of tasks being processed right now
+pub fn busy() -> usize {
+    DISPATCHED.lock().unwrap().len()
+}
+
+/// start the workers and feed them until shutdown
+pub async fn run() {
+    let concurrency = config::current().worker.concurrency.max(1);
+    let (tx, rx) = mpsc::channel::<Job>(concurrency);
+    let rx = Arc::new(tokio::sync::Mutex::new(rx));
+    for id in 0..concurrency {
+        tokio::spawn(worker(id, rx.clone()));
+    }
+    info!("started {} task workers", concurrency);
+
+    let idle = Arc::new(Semaphore::new(concurrency));
+    loop {
+        let permit = idle.clone().acquire_owned().await.expect("the semaphore is never closed");
+        if shutdown::requested() {
+            break
+        }
+        if admin::processing_paused() {
+            drop(permit);
+            time::sleep(Duration::from_secs(IDLE_POLL_SECS)).await;
+            continue
+        }
+        let next = {
+            let queue = TASK_QUEUE.lock().await;
+            let mut dispatched = DISPATCHED.lock().unwrap();
+            let next = queue.next_ready(&dispatched).cloned();
+            if let Some(t) = &next {
+                dispatched.insert(t.id);
+                info!(" start to process the task data of len : {}", queue.len());
+            }
+            next
+        };
+        match next {
+            Some(task) => {
+                let job = Job { task, _idle: permit, _work: shutdown::begin_work() };
+                if tx.send(job).await.is_err() {
+                    error!("all task workers stopped");
+                    break
+                }
+            },
+            None => {
+                drop(permit);
+                let _ = time::timeout(Duration::from_secs(IDLE_POLL_SECS), QUEUED.notified()).await;
+            },
+        }
+    }
+}
+
+async fn worker(id: usize, jobs: Arc<tokio::sync::Mutex<mpsc::Receiver<Job>>>) {
+    loop {
+        let job = match jobs.lock().await.recv().await {
+            Some(j) => j,
+            None => return,
+        };
+        let task_id = job.task.id;
+        if let Err(e) = tokio::spawn(process(job.task.clone())).await {
+            error!("worker {} failed on task {}: {}", id, job.task.key, e);
+            settle_panicked(&job.task, &e.to_string()).await;
+        }
+        DISPATCHED.lock().unwrap().remove(&task_id);
+    }
+}
+
+/// a task whose worker panicked is left mid-submission and can not be retried, so ack it:
+/// as broadcast when its tx is already out, failed otherwise
+async fn settle_panicked(item: &QueuedTask, reason: &str) {
+    let journaled = TASK_QUEUE.lock().await.broadcast_outcome(item.id).cloned();
+    let outcome = match (task::get_task(&item.key).await, journaled) {
+        (Some(t), _) if t.tx_hash.is_some() => t,
+        (_, Some(t)) => {
+            task::insert_task(t.clone()).await;
+            t
+        },
+        _ => {
+            task::fail(&item.key, &format!("worker panicked: {}", reason)).await;
+            task::get_task(&item.key).await.unwrap_or_else(|| Task::new(&item.key))
+        },
+    };
+    let mut queue = TASK_QUEUE.lock().await;
+    if !queue.is_queued(item.id) {
+        return
+    }
+    if let Err(e) = queue.ack(item.id, outcome) {
+        error!("record the outcome of task {} failed: {}", item.key, e);
+    }
+}
+
+async fn process(item: QueuedTask) {
+    let params = match item.params.clone() {
+        Some(p) => p,
+        None => config::current().task.defaults(),
+    };
+    let outcome = process_task_data(&item.key, item.input.clone(), &params, item.network.as_deref()).await;
+    let mut queue = TASK_QUEUE.lock().await;
+    if !queue.is_queued(item.id) {
+        return  //cancelled while it was being processed, already recorded
+    }
+    if let Err(e) = queue.ack(item.id, outcome) {
+        error!("record the outcome of task {} failed: {}", item.key, e);
+    }
+}